@@ -1,23 +1,52 @@
 use std::fmt;
 use std::{
     fs::File, 
-    collections::HashMap,
-    io::prelude::*
+    collections::BTreeMap,
+    io::{self, prelude::*},
+    ops::Range
 };
 use sha1_smol::Sha1;
 
-// Error type if invalid character found
-type Result<T> = std::result::Result<T, InvalidCharError>;
+mod de;
+mod ser;
+mod path;
+pub mod json;
 
-impl fmt::Display for InvalidCharError {
+pub use de::{from_bytes, from_bytes_with, from_element, from_value};
+pub use ser::{to_bytes, to_element};
+pub use path::{parse_path, LookupError, LookupErrorKind, PathSegment};
+
+// Error type returned by every decode path
+type Result<T> = std::result::Result<T, DecodeError>;
+
+impl fmt::Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Invalid char found in bencoded file at index:{}, char:{}",self.index, self.curr)
+        match self {
+            DecodeError::UnexpectedEof { offset } => write!(f, "Unexpected end of bencoded data at index:{}", offset),
+            DecodeError::InvalidChar { offset, curr } => write!(f, "Invalid char found in bencoded data at index:{}, char:{}", offset, curr),
+            DecodeError::NonDigit { offset, curr } => write!(f, "Non digit found in bencoded number at index:{}, char:{}", offset, curr),
+            DecodeError::LeadingZero { offset } => write!(f, "Leading zero in bencoded number at index:{}", offset),
+            DecodeError::NegativeZero { offset } => write!(f, "Negative zero in bencoded integer at index:{}", offset),
+            DecodeError::UnsortedKey { offset } => write!(f, "Dict key out of sorted order at index:{}", offset),
+            DecodeError::DuplicateKey { offset } => write!(f, "Duplicate dict key at index:{}", offset),
+            DecodeError::TrailingData { offset } => write!(f, "Trailing data after bencoded value at index:{}", offset),
+            DecodeError::LengthOverflow { offset } => write!(f, "Byte string length exceeds available data at index:{}", offset),
+            DecodeError::IntegerOverflow { offset } => write!(f, "Bencoded integer does not fit in 64 bits at index:{}", offset),
+            DecodeError::DepthLimit { offset } => write!(f, "Bencoded data nested too deeply at index:{}", offset),
+            DecodeError::StringTooLong { offset } => write!(f, "Byte string longer than allowed at index:{}", offset),
+            DecodeError::TooManyItems { offset } => write!(f, "Container has more items than allowed at index:{}", offset),
+            DecodeError::InputTooLarge { offset } => write!(f, "Bencoded data larger than allowed at index:{}", offset),
+            DecodeError::Io(kind) => write!(f, "Could not read bencoded data: {}", kind)
+        }
     }
 }
 
-#[derive(Debug)]
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Element {
-    Dict(HashMap<Vec<u8>,Element>),
+    // Keys are kept in sorted raw-byte order so encoding is canonical
+    Dict(BTreeMap<Vec<u8>,Element>),
     Integer(i64),
     ByteString(Vec<u8>),
     List(Vec<Element>)
@@ -29,39 +58,360 @@ impl fmt::Display for Element {
     }
 }
 
-#[derive(Debug)]
-pub struct InvalidCharError{
-    index: usize,
-    curr: u8
+impl Element {
+
+    /// Value stored under `key` if this is a dict containing it
+    pub fn get(&self, key: &str) -> Option<&Element> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Element::Integer(i) => Some(*i),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Element::ByteString(s) => Some(s),
+            _ => None
+        }
+    }
+
+    /// Byte string as str, None if it is not valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Element]> {
+        match self {
+            Element::List(l) => Some(l),
+            _ => None
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Element>> {
+        match self {
+            Element::Dict(mp) => Some(mp),
+            _ => None
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Element::Dict(_) => "dict",
+            Element::Integer(_) => "integer",
+            Element::ByteString(_) => "byte string",
+            Element::List(_) => "list"
+        }
+    }
+
+}
+
+/// Bencoded value borrowing from the buffer it was decoded from.
+/// `span` is the range of the value in the source buffer and `raw` the bytes it covers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Value<'a> {
+    pub kind: ValueKind<'a>,
+    pub span: Range<usize>,
+    pub raw: &'a [u8]
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueKind<'a> {
+    // Entries are kept in source order
+    Dict(Vec<(&'a [u8], Value<'a>)>),
+    Integer(i64),
+    ByteString(&'a [u8]),
+    List(Vec<Value<'a>>)
+}
+
+impl<'a> Value<'a> {
+
+    /// Value stored under `key` if this is a dict containing it
+    pub fn get(&self, key: &[u8]) -> Option<&Value<'a>> {
+        match &self.kind {
+            ValueKind::Dict(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    /// Copy the value into an owned Element, for duplicate dict keys the first entry wins
+    pub fn to_element(&self) -> Element {
+        match &self.kind {
+            ValueKind::Integer(i) => Element::Integer(*i),
+            ValueKind::ByteString(s) => Element::ByteString(s.to_vec()),
+            ValueKind::List(l) => Element::List(l.iter().map(Value::to_element).collect()),
+            ValueKind::Dict(entries) => {
+                let mut mp = BTreeMap::new();
+                for (key, value) in entries {
+                    mp.entry(key.to_vec()).or_insert_with(|| value.to_element());
+                }
+                Element::Dict(mp)
+            }
+        }
+    }
+
+}
+
+/// Error returned when bencoded data can not be decoded.
+/// Every variant except `Io` carries the byte offset at which decoding failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEof { offset: usize },
+    InvalidChar { offset: usize, curr: u8 },
+    NonDigit { offset: usize, curr: u8 },
+    LeadingZero { offset: usize },
+    NegativeZero { offset: usize },
+    UnsortedKey { offset: usize },
+    DuplicateKey { offset: usize },
+    TrailingData { offset: usize },
+    LengthOverflow { offset: usize },
+    IntegerOverflow { offset: usize },
+    DepthLimit { offset: usize },
+    StringTooLong { offset: usize },
+    TooManyItems { offset: usize },
+    InputTooLarge { offset: usize },
+    Io(io::ErrorKind)
+}
+
+impl DecodeError {
+    /// Byte offset in the input at which the error was found
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            DecodeError::UnexpectedEof { offset }
+            | DecodeError::InvalidChar { offset, .. }
+            | DecodeError::NonDigit { offset, .. }
+            | DecodeError::LeadingZero { offset }
+            | DecodeError::NegativeZero { offset }
+            | DecodeError::UnsortedKey { offset }
+            | DecodeError::DuplicateKey { offset }
+            | DecodeError::TrailingData { offset }
+            | DecodeError::LengthOverflow { offset }
+            | DecodeError::IntegerOverflow { offset }
+            | DecodeError::DepthLimit { offset }
+            | DecodeError::StringTooLong { offset }
+            | DecodeError::TooManyItems { offset }
+            | DecodeError::InputTooLarge { offset } => Some(offset),
+            DecodeError::Io(_) => None
+        }
+    }
+}
+
+/// Error returned by the serde data format, either the input was not valid bencode
+/// or it did not match the shape of the requested type
 #[derive(Debug)]
-pub struct Bencode{
+pub enum SerdeError {
+    Decode(DecodeError),
+    Message(String)
+}
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerdeError::Decode(e) => write!(f, "{}", e),
+            SerdeError::Message(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl From<DecodeError> for SerdeError {
+    fn from(e: DecodeError) -> Self {
+        SerdeError::Decode(e)
+    }
+}
+
+impl serde::ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+/// How input that is valid but not canonical bencode is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    // Reject leading zeros, negative zero, unsorted and duplicate dict keys
+    Strict,
+    // Accept them and record a DecodeWarning for each
+    Lenient
+}
+
+/// Non-canonical encoding accepted while decoding in lenient mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+    LeadingZero,
+    NegativeZero,
+    UnsortedKey,
+    DuplicateKey
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeWarning {
+    pub offset: usize,
+    pub kind: WarningKind
+}
+
+impl DecodeWarning {
+    // Error reported for the same input in strict mode
+    fn to_error(self) -> DecodeError {
+        let offset = self.offset;
+        match self.kind {
+            WarningKind::LeadingZero => DecodeError::LeadingZero { offset },
+            WarningKind::NegativeZero => DecodeError::NegativeZero { offset },
+            WarningKind::UnsortedKey => DecodeError::UnsortedKey { offset },
+            WarningKind::DuplicateKey => DecodeError::DuplicateKey { offset }
+        }
+    }
+}
+
+impl fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_error())
+    }
+}
+
+/// Limits applied while decoding, input exceeding any of them fails with an error instead
+/// of recursing or allocating without bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    // Maximum nesting of lists and dicts
+    pub max_depth: usize,
+    // Maximum declared length of a single byte string
+    pub max_string_len: usize,
+    // Maximum number of items in a single list or entries in a single dict
+    pub max_items: usize,
+    // Maximum size in bytes of the whole value
+    pub max_total_size: usize,
+    pub validation: Validation
+}
+
+impl Default for DecodeOptions {
+    // Local files are trusted, only nesting is bounded so the decoder can not overflow the stack
+    fn default() -> Self {
+        DecodeOptions {
+            max_depth: 256,
+            max_string_len: usize::MAX,
+            max_items: usize::MAX,
+            max_total_size: usize::MAX,
+            validation: Validation::Strict
+        }
+    }
+}
+
+impl DecodeOptions {
+
+    /// Conservative limits for data received from trackers and peers
+    pub fn untrusted() -> DecodeOptions {
+        DecodeOptions {
+            max_depth: 32,
+            max_string_len: 1 << 20,
+            max_items: 1 << 14,
+            max_total_size: 4 << 20,
+            validation: Validation::Strict
+        }
+    }
+
+    /// Same limits with non-canonical input accepted
+    pub fn lenient(self) -> DecodeOptions {
+        DecodeOptions { validation: Validation::Lenient, ..self }
+    }
+
+}
+
+/// Outcome of decoding from input that may be incomplete
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress<T> {
+    // Input ended before a whole value was read
+    NeedMore,
+    // A whole value was read, `end` is the offset just past it
+    Done { value: T, end: usize }
+}
+
+/// Decoder that is fed input in chunks as it arrives, for example from a socket.
+/// Bytes after a decoded value are kept for the next call or can be taken with `take_remaining`
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
     buf: Vec<u8>,
+    opts: DecodeOptions
+}
+
+impl StreamDecoder {
+
+    pub fn new() -> StreamDecoder {
+        StreamDecoder::default()
+    }
+
+    pub fn with_options(opts: DecodeOptions) -> StreamDecoder {
+        StreamDecoder { buf: Vec::new(), opts }
+    }
+
+    /// Append received bytes to the pending input
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Try to decode the next value from pending input.
+    /// On success the value's bytes are consumed and `end` is its length in the pending input
+    pub fn decode(&mut self) -> Result<Progress<Element>> {
+        let progress = match Bencode::decode_prefix_with(&self.buf, &self.opts)? {
+            Progress::NeedMore => Progress::NeedMore,
+            Progress::Done { value, end } => Progress::Done { value: value.to_element(), end }
+        };
+        if let Progress::Done { end, .. } = progress {
+            self.buf.drain(..end);
+        }
+        Ok(progress)
+    }
+
+    /// Bytes fed but not consumed by a decoded value
+    pub fn remaining(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Take bytes fed but not consumed by a decoded value
+    pub fn take_remaining(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+}
+
+#[derive(Debug)]
+pub struct Bencode<'a>{
+    buf: &'a [u8],
     ind: usize,
-    curr: u8,
-    info_ind: (i32,i32)
+    depth: usize,
+    opts: DecodeOptions,
+    warnings: Vec<DecodeWarning>
 }
 
-impl Bencode {
+impl<'a> Bencode<'a> {
 
-    fn new(buf: Vec<u8>) -> Bencode {
+    fn new(buf: &'a [u8], opts: &DecodeOptions) -> Bencode<'a> {
 
-        Bencode { buf, ind: 0, curr: 0, info_ind: (-1,-1)}
+        Bencode { buf, ind: 0, depth: 0, opts: *opts, warnings: Vec::new() }
 
     }
 
     ///Decode Bencoded file
-    ///Accepts file that is in bencoded format and returns entire bencoded dictionary in element along with hash
-    pub fn decode(f: &mut File) -> Result<(Element, [u8;20])> {
+    ///Accepts file that is in bencoded format and returns entire bencoded dictionary in element along with info hash if an info dict is present.
+    ///Real torrents are often not canonical so the file is decoded leniently and any problems are returned as warnings
+    pub fn decode(f: &mut File) -> Result<(Element, Option<[u8;20]>, Vec<DecodeWarning>)> {
 
         // Create a buff reader and read the entire .torrent file into buf as bytes
         let mut buf = Vec::new();
-        f.read_to_end(&mut buf).unwrap();
+        f.read_to_end(&mut buf).map_err(|e| DecodeError::Io(e.kind()))?;
 
-        // Create a new instance of Bencode and start parsing
-        let mut instance = Bencode::new(buf);
-        Ok(( instance.call_element().unwrap() , instance.calculate_hash() ))
+        let (value, warnings) = Bencode::decode_value_with_warnings(&buf, &DecodeOptions::default().lenient())?;
+        Ok(( value.to_element(), Bencode::info_hash(&value), warnings ))
 
     }
 
@@ -69,211 +419,340 @@ impl Bencode {
     ///Accepts bencoded [u8] and return bencoded dictionary
     pub fn decode_u8(buf: Vec<u8>) -> Result<Element> {
         
-        let mut instance = Bencode::new(buf);
-        Ok(instance.call_element().unwrap())
+        Ok(Bencode::decode_value(&buf)?.to_element())
 
     }
 
-    fn calculate_hash(&mut self) -> [u8;20] {
+    ///Decode bencoded [u8] without copying
+    ///Returns a Value borrowing from buf, the whole buffer must be a single value
+    pub fn decode_value(buf: &'a [u8]) -> Result<Value<'a>> {
 
-        // Define hahser
-        let mut hasher = Sha1::new();
+        Bencode::decode_value_with(buf, &DecodeOptions::default())
+
+    }
+
+    ///Decode bencoded [u8] without copying, failing if any limit in opts is exceeded
+    pub fn decode_value_with(buf: &'a [u8], opts: &DecodeOptions) -> Result<Value<'a>> {
+
+        Ok(Bencode::decode_value_with_warnings(buf, opts)?.0)
+
+    }
+
+    ///Decode bencoded [u8] without copying
+    ///Also returns the non-canonical encodings found, which is always empty in strict mode
+    pub fn decode_value_with_warnings(buf: &'a [u8], opts: &DecodeOptions) -> Result<(Value<'a>, Vec<DecodeWarning>)> {
+
+        if buf.len() > opts.max_total_size {
+            return Err(DecodeError::InputTooLarge { offset: opts.max_total_size });
+        }
+
+        let mut instance = Bencode::new(buf, opts);
+        let value = instance.call_element()?;
+        if instance.ind != buf.len() {
+            return Err(DecodeError::TrailingData { offset: instance.ind });
+        }
+        Ok((value, instance.warnings))
 
-        // Create info string
-        let mut info_string = Vec::new();
-        for ind in self.info_ind.0..self.info_ind.1 {
-            info_string.push(self.buf[ind as usize]);
+    }
+
+    ///Decode the bencoded value at the start of buf
+    ///Data after the value is allowed and the returned `end` is where the value stopped,
+    ///NeedMore is returned if buf is a truncated value
+    pub fn decode_prefix(buf: &'a [u8]) -> Result<Progress<Value<'a>>> {
+
+        Bencode::decode_prefix_with(buf, &DecodeOptions::default())
+
+    }
+
+    ///Decode the bencoded value at the start of buf, failing if any limit in opts is exceeded
+    pub fn decode_prefix_with(buf: &'a [u8], opts: &DecodeOptions) -> Result<Progress<Value<'a>>> {
+
+        // Only look at as much input as the value is allowed to take
+        let limit = buf.len().min(opts.max_total_size);
+        let mut instance = Bencode::new(&buf[..limit], opts);
+        match instance.call_element() {
+            Ok(value) => Ok(Progress::Done { value, end: instance.ind }),
+            // The value continues past the largest size allowed
+            Err(DecodeError::UnexpectedEof { .. }) | Err(DecodeError::LengthOverflow { .. }) if limit == opts.max_total_size => {
+                Err(DecodeError::InputTooLarge { offset: limit })
+            },
+            // Both mean the value continues past the end of buf
+            Err(DecodeError::UnexpectedEof { .. }) | Err(DecodeError::LengthOverflow { .. }) => Ok(Progress::NeedMore),
+            Err(e) => Err(e)
         }
 
-        // Update hasher and generate hash
-        hasher.update(&info_string);
-        hasher.digest().bytes()
+    }
+
+    ///Info hash of a decoded torrent
+    ///SHA-1 of the raw bytes of the top-level info value, None if there is no such key
+    pub fn info_hash(decoded: &Value) -> Option<[u8;20]> {
+
+        let info = decoded.get(b"info")?;
+        let mut hasher = Sha1::new();
+        hasher.update(info.raw);
+        Some(hasher.digest().bytes())
 
     }
 
-    pub fn encode(decoded: &Element) -> String {
-        let mut encoded = String::new();
+    ///Encode Element
+    ///Returns the canonical bencoding of the element, dictionary keys are emitted in sorted raw-byte order
+    pub fn encode(decoded: &Element) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        // Writing into a Vec can not fail
+        Bencode::encode_to(decoded, &mut encoded).unwrap();
+        encoded
+    }
+
+    ///Encode Element into writer
+    ///Writes the canonical bencoding of the element into any writer
+    pub fn encode_to<W: Write>(decoded: &Element, w: &mut W) -> io::Result<()> {
         match decoded {
             Element::ByteString(s) => {
-                encoded.push_str(s.len().to_string().as_str());
-                encoded.push(':');
-                encoded.push_str(&String::from_utf8(s.to_owned()).unwrap());
+                Bencode::write_byte_string(s, w)?;
             },
             Element::Dict(mp) => {
-                encoded.push('d');
-                for (s,element) in mp {
-                    encoded.push_str(s.len().to_string().as_str());
-                    encoded.push(':');
-                    encoded.push_str(&String::from_utf8(s.to_owned()).unwrap());
-                    encoded.push_str(Bencode::encode(element).as_str());
+                w.write_all(b"d")?;
+                // BTreeMap iterates in sorted key order as required by BEP 3
+                for (key, element) in mp {
+                    Bencode::write_byte_string(key, w)?;
+                    Bencode::encode_to(element, w)?;
                 }
-                encoded.push('e');
-            }, 
+                w.write_all(b"e")?;
+            },
             Element::Integer(i) => {
-                encoded.push('i');
-                encoded.push_str(i.to_string().as_str());
-                encoded.push('e');
+                write!(w, "i{}e", i)?;
             },
             Element::List(l) => {
-                encoded.push('l');
+                w.write_all(b"l")?;
                 for element in l {
-                    encoded.push_str(Bencode::encode(element).as_str());
+                    Bencode::encode_to(element, w)?;
                 }
-                encoded.push('e');
+                w.write_all(b"e")?;
             }
         }
-        return encoded;
+        Ok(())
+    }
+
+    // 10:abcdefghij
+    fn write_byte_string<W: Write>(s: &[u8], w: &mut W) -> io::Result<()> {
+        write!(w, "{}:", s.len())?;
+        w.write_all(s)
     }
 
 
     // Match element using first character and call element to parse respective element
-    fn call_element(&mut self) -> Result<Element> {
+    fn call_element(&mut self) -> Result<Value<'a>> {
 
-        match self.read_char() as char {
+        let start = self.ind;
+        let kind = match self.peek_char()? {
 
-            'd' => self.read_dict(),
-            '0'..='9' => self.read_byte_string(),
-            'l' => self.read_list(),
-            'i' => self.read_int(),
+            b'd' => self.read_dict()?,
+            b'0'..=b'9' => ValueKind::ByteString(self.read_bytes()?),
+            b'l' => self.read_list()?,
+            b'i' => self.read_int()?,
             // If none of the above found return invalid character
-            _ => Err(InvalidCharError{index: self.ind, curr:self.get_char() as u8})
+            curr => return Err(DecodeError::InvalidChar { offset: self.ind, curr })
 
-        }
+        };
+
+        Ok(Value { kind, span: start..self.ind, raw: &self.buf[start..self.ind] })
 
     }
 
 
     // d.....e
-    // .'
+    // '
     // keys are only byte strings
-    fn read_dict(&mut self) -> Result<Element> {
+    fn read_dict(&mut self) -> Result<ValueKind<'a>> {
 
-        // Create a hashmap to store the Dict
-        let mut mp = HashMap::new();
+        // Create a vector to store the Dict entries in order
+        let mut entries = Vec::new();
+        let mut unsorted = false;
+        self.enter_container()?;
 
         // loop until end of Dict found
-        'outer: loop {
+        while self.peek_char()? != b'e' {
 
-            // read extra char as read_byte_string will unread char
-            self.read_char();
+            self.check_items(entries.len())?;
 
             // Key of the Dict is always a ByteString so first read key
-            if let Element::ByteString(key1) = self.read_byte_string()? {
-                
-                let key = String::from_utf8_lossy(&key1);
-
-                if key == "info" {
-                    self.info_ind.0 = self.ind as i32;
-
-                    let value = self.call_element()?;
-                    mp.insert(key1, value);
-
-                    self.info_ind.1 = self.ind as i32;
-
+            let key_start = self.ind;
+            let key = match self.peek_char()? {
+                b'0'..=b'9' => self.read_bytes()?,
+                curr => return Err(DecodeError::InvalidChar { offset: self.ind, curr })
+            };
+
+            // Keys must be unique and in sorted order. Duplicates can only be missed by comparing
+            // with the previous key once the dict is out of order, so search for them from then on
+            let out_of_order = entries.last().is_some_and(|(prev, _)| key <= *prev);
+            unsorted |= out_of_order;
+            if unsorted {
+                if entries.iter().any(|(k, _)| *k == key) {
+                    self.non_canonical(WarningKind::DuplicateKey, key_start)?;
                 }
-                else {
-
-                    // parse value which can be any Element and insert key value pair in HashMap
-                    let value = self.call_element()?;
-                    mp.insert(key1, value); 
-
+                else if out_of_order {
+                    self.non_canonical(WarningKind::UnsortedKey, key_start)?;
                 }
-
-            }
-
-            // Break if at end of dict
-            if self.read_char() == 'e' as u8 {
-                break 'outer;
             }
 
-            // if char not end than unread
-            self.unread_char();
+            // parse value which can be any Element
+            let value = self.call_element()?;
+            entries.push((key, value));
 
         }
+        self.read_char()?;
+        self.depth -= 1;
 
-        Ok(Element::Dict(mp))
+        Ok(ValueKind::Dict(entries))
 
     }
 
 
     // 10:abcdefghij
-    // .'
-    fn read_byte_string(&mut self) -> Result<Element> {
-
-        // Unread char as extra char read
-        self.unread_char();
-        let mut sz: u64 = 0;
+    // '
+    fn read_bytes(&mut self) -> Result<&'a [u8]> {
 
         // get size of string
-        while self.read_char() != ':' as u8 {
-            sz *= 10;
-            sz += (self.get_char() as u8 - '0' as u8) as u64;
+        let start = self.ind;
+        let sz = self.read_number(b':')?;
+        if sz.1 {
+            return Err(DecodeError::NonDigit { offset: start, curr: b'-' });
         }
 
-        let mut s = Vec::new();
-
-        // get string
-        for _ in 0..sz {
-            s.push(self.read_char()); 
+        // get string, the declared length must be allowed and fit in the remaining buffer
+        if sz.0 > self.opts.max_string_len as u64 {
+            return Err(DecodeError::StringTooLong { offset: start });
         }
-        
-        Ok(Element::ByteString(s))
+        let remaining = self.buf.len() - self.ind;
+        if sz.0 > remaining as u64 {
+            return Err(DecodeError::LengthOverflow { offset: start });
+        }
+        let end = self.ind + sz.0 as usize;
+        let s = &self.buf[self.ind..end];
+        self.ind = end;
+
+        Ok(s)
 
     }
 
 
     // i324e
-    // .'
-    fn read_int(&mut self) -> Result<Element> {
-
-        let mut fin: i64 = 0;
-        let mut mult: i64 = 1;
-        // read integer until end char recieved
-        while self.read_char() != 'e' as u8 {
-            if self.get_char() == '-' as u8 {
-                mult = -1;
-                continue;
+    // '
+    fn read_int(&mut self) -> Result<ValueKind<'a>> {
+
+        self.read_char()?;
+        let start = self.ind;
+        let (abs, negative) = self.read_number(b'e')?;
+
+        if negative && abs == 0 {
+            self.non_canonical(WarningKind::NegativeZero, start)?;
+        }
+
+        // i64::MIN has no positive counterpart so handle the sign before converting
+        let fin = if negative {
+            0i64.checked_sub_unsigned(abs)
+        } else {
+            i64::try_from(abs).ok()
+        };
+
+        fin.map(ValueKind::Integer).ok_or(DecodeError::IntegerOverflow { offset: start })
+    }
+
+    // Read an optionally negative decimal number terminated by `end`, rejecting leading zeros.
+    // Returns absolute value and whether it was negative
+    fn read_number(&mut self, end: u8) -> Result<(u64, bool)> {
+
+        let start = self.ind;
+        let negative = self.peek_char()? == b'-';
+        if negative {
+            self.read_char()?;
+        }
+
+        let digits_start = self.ind;
+        let mut fin: u64 = 0;
+        loop {
+            let offset = self.ind;
+            match self.read_char()? {
+                c @ b'0'..=b'9' => {
+                    if offset == digits_start + 1 && self.buf[digits_start] == b'0' {
+                        self.non_canonical(WarningKind::LeadingZero, start)?;
+                    }
+                    fin = fin
+                        .checked_mul(10)
+                        .and_then(|v| v.checked_add((c - b'0') as u64))
+                        .ok_or(DecodeError::IntegerOverflow { offset: start })?;
+                },
+                c if c == end && offset != digits_start => break,
+                curr => return Err(DecodeError::NonDigit { offset, curr })
             }
-            fin *= 10;
-            fin += (self.get_char() as u8 - '0' as u8) as i64;
         }
-        fin *= mult;
-        Ok(Element::Integer(fin))
+
+        Ok((fin, negative))
+
     }
 
 
     // l....e
-    // .'
-    fn read_list(&mut self) -> Result<Element> {
+    // '
+    fn read_list(&mut self) -> Result<ValueKind<'a>> {
         let mut v = Vec::new();
+        self.enter_container()?;
 
         // Read elements until end char recived
-        while self.read_char() != 'e' as u8 {
-            self.unread_char();
+        while self.peek_char()? != b'e' {
+            self.check_items(v.len())?;
             v.push(self.call_element()?);
         }
+        self.read_char()?;
+        self.depth -= 1;
 
-        Ok(Element::List(v))
+        Ok(ValueKind::List(v))
 
     }
 
-    // Function to return next char in buffer
-    fn read_char(&mut self) -> u8 {
-        let tmp = self.buf[self.ind];
-        self.curr = tmp;
-        self.ind += 1;
-        tmp
+    // Fail in strict mode, record a warning in lenient mode
+    fn non_canonical(&mut self, kind: WarningKind, offset: usize) -> Result<()> {
+        let warning = DecodeWarning { offset, kind };
+        match self.opts.validation {
+            Validation::Strict => Err(warning.to_error()),
+            Validation::Lenient => {
+                self.warnings.push(warning);
+                Ok(())
+            }
+        }
     }
 
-    // Return currently read char
-    fn get_char(&self) -> u8 {
-        self.curr
+    // Consume the opening char of a list or dict
+    fn enter_container(&mut self) -> Result<()> {
+        if self.depth >= self.opts.max_depth {
+            return Err(DecodeError::DepthLimit { offset: self.ind });
+        }
+        self.depth += 1;
+        self.read_char()?;
+        Ok(())
+    }
+
+    // Called before reading another item into a container already holding count items
+    fn check_items(&self, count: usize) -> Result<()> {
+        if count >= self.opts.max_items {
+            return Err(DecodeError::TooManyItems { offset: self.ind });
+        }
+        Ok(())
+    }
+
+    // Function to return next char in buffer
+    fn read_char(&mut self) -> Result<u8> {
+        let tmp = self.peek_char()?;
+        self.ind += 1;
+        Ok(tmp)
     }
 
-    // Unread a character in current buffer 
-    fn unread_char(&mut self) {
-        self.ind -= 1;
-        self.curr = self.buf[self.ind];
+    // Return next char without consuming it
+    fn peek_char(&self) -> Result<u8> {
+        self.buf
+            .get(self.ind)
+            .copied()
+            .ok_or(DecodeError::UnexpectedEof { offset: self.ind })
     }
     
 }
@@ -281,5 +760,244 @@ impl Bencode {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+    use serde::{Deserialize, Serialize};
+    use sha1_smol::Sha1;
+    use super::{from_bytes, to_bytes, Bencode, DecodeError, DecodeOptions, DecodeWarning, Element, Progress, StreamDecoder, ValueKind, WarningKind};
+
+    // Small single file torrent with binary piece hashes
+    fn sample_torrent() -> Vec<u8> {
+        let mut buf = b"d8:announce21:udp://tracker.test:804:infod6:lengthi40000e4:name8:test.bin12:piece lengthi32768e6:pieces40:".to_vec();
+        buf.extend((0..40u8).map(|i| i.wrapping_mul(37) ^ 0xC3));
+        buf.extend(b"ee");
+        buf
+    }
+
+    #[test]
+    fn encode_sorts_keys() {
+        let mut mp = BTreeMap::new();
+        mp.insert(b"zz".to_vec(), Element::Integer(-3));
+        mp.insert(b"a".to_vec(), Element::List(vec![Element::ByteString(vec![0xff, 0x00])]));
+        mp.insert(b"ab".to_vec(), Element::Integer(0));
+
+        assert_eq!(Bencode::encode(&Element::Dict(mp)), b"d1:al2:\xff\x00e2:abi0e2:zzi-3ee".to_vec());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let buf = sample_torrent();
+        let decoded = Bencode::decode_u8(buf.clone()).unwrap();
+        assert_eq!(Bencode::encode(&decoded), buf);
+
+        // Re-encoded info dict reproduces the info hash
+        let Element::Dict(mp) = decoded else { panic!("expected dict") };
+        let mut hasher = Sha1::new();
+        hasher.update(&Bencode::encode(&mp[b"info".as_slice()]));
+        assert_eq!(Some(hasher.digest().bytes()), Bencode::info_hash(&Bencode::decode_value(&buf).unwrap()));
+    }
+
+    #[test]
+    fn value_spans() {
+        let buf = sample_torrent();
+        let value = Bencode::decode_value(&buf).unwrap();
+        assert_eq!(value.span, 0..buf.len());
+
+        let info = value.get(b"info").unwrap();
+        let start = buf.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        assert_eq!(info.span, start..buf.len() - 1);
+
+        // Byte strings borrow from the source buffer
+        let pieces = info.get(b"pieces").unwrap();
+        let ValueKind::ByteString(s) = pieces.kind else { panic!("expected byte string") };
+        assert_eq!(s.as_ptr(), buf[pieces.span.end - 40..].as_ptr());
+        assert_eq!(pieces.raw, &buf[pieces.span.clone()]);
+    }
+
+    #[test]
+    fn info_hash_uses_top_level_info() {
+        // A nested info key after the real one must not change the hash
+        let buf = b"d4:infod1:ai1ee5:otherd4:infoi2eee".to_vec();
+        let mut hasher = Sha1::new();
+        hasher.update(b"d1:ai1ee");
+        assert_eq!(Bencode::info_hash(&Bencode::decode_value(&buf).unwrap()), Some(hasher.digest().bytes()));
+        assert_eq!(Bencode::info_hash(&Bencode::decode_value(b"d5:otherd4:infoi2eee").unwrap()), None);
+    }
 
+    #[test]
+    fn decode_errors() {
+        let cases: Vec<(&[u8], DecodeError)> = vec![
+            (b"", DecodeError::UnexpectedEof { offset: 0 }),
+            (b"d3:foo", DecodeError::UnexpectedEof { offset: 6 }),
+            (b"10:abc", DecodeError::LengthOverflow { offset: 0 }),
+            (b"i12a4e", DecodeError::NonDigit { offset: 3, curr: b'a' }),
+            (b"3x:abc", DecodeError::NonDigit { offset: 1, curr: b'x' }),
+            (b"ie", DecodeError::NonDigit { offset: 1, curr: b'e' }),
+            (b"i007e", DecodeError::LeadingZero { offset: 1 }),
+            (b"03:abc", DecodeError::LeadingZero { offset: 0 }),
+            (b"i-0e", DecodeError::NegativeZero { offset: 1 }),
+            (b"d1:bi1e1:ai2ee", DecodeError::UnsortedKey { offset: 7 }),
+            (b"d1:ai1e1:ai2ee", DecodeError::DuplicateKey { offset: 7 }),
+            (b"-3:abc", DecodeError::InvalidChar { offset: 0, curr: b'-' }),
+            (b"i99999999999999999999e", DecodeError::IntegerOverflow { offset: 1 }),
+            (b"di1ei2ee", DecodeError::InvalidChar { offset: 1, curr: b'i' }),
+            (b"x", DecodeError::InvalidChar { offset: 0, curr: b'x' }),
+            (b"i1ei2e", DecodeError::TrailingData { offset: 3 }),
+        ];
+
+        for (input, err) in cases {
+            assert_eq!(Bencode::decode_u8(input.to_vec()).unwrap_err(), err, "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn decode_prefix_reports_end() {
+        // ut_metadata style message, dict followed by raw payload
+        let buf = b"d8:msg_typei1e5:piecei0eeRAWDATA";
+        let Progress::Done { value, end } = Bencode::decode_prefix(buf).unwrap() else { panic!("expected value") };
+        assert_eq!(end, 25);
+        assert_eq!(value.raw, &buf[..25]);
+        assert_eq!(&buf[end..], b"RAWDATA");
+
+        assert_eq!(Bencode::decode_prefix(b"d8:msg_typ").unwrap(), Progress::NeedMore);
+        assert_eq!(Bencode::decode_prefix(b"").unwrap(), Progress::NeedMore);
+        assert!(Bencode::decode_prefix(b"i1x").is_err());
+    }
+
+    #[test]
+    fn stream_decoder_chunks() {
+        let mut decoder = StreamDecoder::new();
+        let input = b"l4:spami42eei7e4:ta";
+
+        // Feed one byte at a time until the first value is complete
+        let mut fed = 0;
+        let first = loop {
+            decoder.feed(&input[fed..fed + 1]);
+            fed += 1;
+            if let Progress::Done { value, end } = decoder.decode().unwrap() {
+                break (value, end);
+            }
+        };
+        assert_eq!(fed, 12);
+        assert_eq!(first.1, 12);
+        assert_eq!(Bencode::encode(&first.0), b"l4:spami42ee");
+
+        decoder.feed(&input[fed..]);
+        let Progress::Done { value: Element::Integer(7), end: 3 } = decoder.decode().unwrap() else { panic!("expected i7e") };
+        assert_eq!(decoder.decode().unwrap(), Progress::NeedMore);
+        assert_eq!(decoder.take_remaining(), b"4:ta");
+    }
+
+    #[test]
+    fn lenient_warnings() {
+        let buf = b"d1:bi007e1:ai-0e1:b03:abce";
+        let (value, warnings) = Bencode::decode_value_with_warnings(buf, &DecodeOptions::default().lenient()).unwrap();
+        assert_eq!(warnings, vec![
+            DecodeWarning { offset: 5, kind: WarningKind::LeadingZero },
+            DecodeWarning { offset: 9, kind: WarningKind::UnsortedKey },
+            DecodeWarning { offset: 13, kind: WarningKind::NegativeZero },
+            DecodeWarning { offset: 16, kind: WarningKind::DuplicateKey },
+            DecodeWarning { offset: 19, kind: WarningKind::LeadingZero },
+        ]);
+
+        // First entry wins for duplicate keys
+        let mut expected = std::collections::BTreeMap::new();
+        expected.insert(b"a".to_vec(), Element::Integer(0));
+        expected.insert(b"b".to_vec(), Element::Integer(7));
+        assert_eq!(value.to_element(), Element::Dict(expected));
+
+        // Canonical input has no warnings and strict mode rejects the first problem
+        assert!(Bencode::decode_value_with_warnings(&sample_torrent(), &DecodeOptions::default().lenient()).unwrap().1.is_empty());
+        assert_eq!(Bencode::decode_value(buf).unwrap_err(), DecodeError::LeadingZero { offset: 5 });
+    }
+
+    #[test]
+    fn decode_limits() {
+        let opts = DecodeOptions { max_depth: 2, max_string_len: 4, max_items: 3, max_total_size: 16, ..DecodeOptions::default() };
+
+        assert!(Bencode::decode_value_with(b"lli1eee", &opts).is_ok());
+        assert_eq!(Bencode::decode_value_with(b"llli1eeee", &opts).unwrap_err(), DecodeError::DepthLimit { offset: 2 });
+        assert_eq!(Bencode::decode_value_with(b"5:abcde", &opts).unwrap_err(), DecodeError::StringTooLong { offset: 0 });
+        assert_eq!(Bencode::decode_value_with(b"li1ei2ei3ei4ee", &opts).unwrap_err(), DecodeError::TooManyItems { offset: 10 });
+        assert_eq!(Bencode::decode_value_with(b"d1:ai1e1:bi2e1:ci3e1:di4ee", &opts).unwrap_err(), DecodeError::InputTooLarge { offset: 16 });
+
+        // Huge declared lengths fail straight away instead of waiting for more data
+        assert_eq!(Bencode::decode_prefix_with(b"99999999999:", &DecodeOptions::untrusted()).unwrap_err(), DecodeError::StringTooLong { offset: 0 });
+        let opts = DecodeOptions { max_total_size: 16, ..DecodeOptions::default() };
+        assert_eq!(Bencode::decode_prefix_with(b"li1ei2ei3ei4ei5ei6e", &opts).unwrap_err(), DecodeError::InputTooLarge { offset: 16 });
+        assert_eq!(Bencode::decode_prefix_with(b"li1ei2e", &opts).unwrap(), Progress::NeedMore);
+    }
+
+    #[test]
+    fn decode_integers() {
+        for (input, val) in [(b"i0e".as_slice(), 0), (b"i-42e", -42), (b"i9223372036854775807e", i64::MAX), (b"i-9223372036854775808e", i64::MIN)] {
+            match Bencode::decode_u8(input.to_vec()).unwrap() {
+                Element::Integer(i) => assert_eq!(i, val),
+                other => panic!("expected integer, got {}", other)
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Info {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u64,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        length: Option<u64>,
+        #[serde(default)]
+        private: bool,
+        #[serde(flatten)]
+        extra: BTreeMap<String, String>
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Metainfo {
+        announce: String,
+        #[serde(rename = "announce-list", default)]
+        announce_list: Option<Vec<Vec<String>>>,
+        info: Info
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let buf = sample_torrent();
+        let mut metainfo: Metainfo = from_bytes(&buf).unwrap();
+        assert_eq!(metainfo.announce, "udp://tracker.test:80");
+        assert_eq!(metainfo.announce_list, None);
+        assert_eq!(metainfo.info.length, Some(40000));
+        assert_eq!(metainfo.info.pieces.len(), 40);
+        assert!(!metainfo.info.private);
+        assert!(metainfo.info.extra.is_empty());
+
+        metainfo.info.private = true;
+        metainfo.info.extra.insert("source".to_string(), "test".to_string());
+        let encoded = to_bytes(&metainfo).unwrap();
+        assert!(encoded.windows(10).any(|w| w == b"7:privatei"));
+        assert_eq!(from_bytes::<Metainfo>(&encoded).unwrap(), metainfo);
+    }
+
+    #[derive(Deserialize)]
+    struct BorrowedInfo<'a> {
+        name: &'a str,
+        #[serde(borrow)]
+        pieces: &'a [u8]
+    }
+
+    #[test]
+    fn serde_borrows() {
+        let buf = sample_torrent();
+        let info: BorrowedInfo = super::from_value(Bencode::decode_value(&buf).unwrap().get(b"info").unwrap().clone()).unwrap();
+        assert_eq!(info.name, "test.bin");
+        assert_eq!(info.pieces, &buf[buf.len() - 42..buf.len() - 2]);
+    }
+
+    #[test]
+    fn serde_errors() {
+        assert!(from_bytes::<Metainfo>(b"d8:announce3:urle").is_err());
+        assert!(from_bytes::<String>(b"2:\xff\xfe").is_err());
+        assert!(from_bytes::<u8>(b"i256e").is_err());
+        assert!(to_bytes(&1.5f64).is_err());
+    }
 }
@@ -0,0 +1,219 @@
+use serde::de::{
+    self, Deserialize, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor
+};
+use super::{Bencode, DecodeOptions, Element, SerdeError, Value, ValueKind};
+
+type Result<T> = std::result::Result<T, SerdeError>;
+
+///Deserialize bencoded [u8] into any type implementing Deserialize
+///Byte strings and strings can be borrowed from buf without copying
+pub fn from_bytes<'de, T: Deserialize<'de>>(buf: &'de [u8]) -> Result<T> {
+    from_value(Bencode::decode_value(buf)?)
+}
+
+///Deserialize bencoded [u8] into any type implementing Deserialize, failing if any limit in opts is exceeded
+pub fn from_bytes_with<'de, T: Deserialize<'de>>(buf: &'de [u8], opts: &DecodeOptions) -> Result<T> {
+    from_value(Bencode::decode_value_with(buf, opts)?)
+}
+
+///Deserialize an already decoded Value into any type implementing Deserialize
+pub fn from_value<'de, T: Deserialize<'de>>(value: Value<'de>) -> Result<T> {
+    T::deserialize(ValueDeserializer(value.kind))
+}
+
+///Deserialize an owned Element into any type implementing Deserialize
+pub fn from_element<T: de::DeserializeOwned>(element: &Element) -> Result<T> {
+    from_bytes(&Bencode::encode(element))
+}
+
+// Deserializer walking a decoded Value tree
+struct ValueDeserializer<'de>(ValueKind<'de>);
+
+impl<'de> ValueDeserializer<'de> {
+    fn kind(&self) -> de::Unexpected<'_> {
+        match &self.0 {
+            ValueKind::Integer(i) => de::Unexpected::Signed(*i),
+            ValueKind::ByteString(s) => de::Unexpected::Bytes(s),
+            ValueKind::List(_) => de::Unexpected::Seq,
+            ValueKind::Dict(_) => de::Unexpected::Map
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            ValueKind::Integer(i) => visitor.visit_i64(i),
+            // Prefer strings so flattened and untagged structs can match on them
+            ValueKind::ByteString(s) => match std::str::from_utf8(s) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => visitor.visit_borrowed_bytes(s)
+            },
+            ValueKind::List(l) => visitor.visit_seq(ListAccess(l.into_iter())),
+            ValueKind::Dict(entries) => visitor.visit_map(DictAccess { iter: entries.into_iter(), value: None })
+        }
+    }
+
+    // Bencode has no booleans, they are stored as i0e and i1e
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            ValueKind::Integer(0) => visitor.visit_bool(false),
+            ValueKind::Integer(1) => visitor.visit_bool(true),
+            _ => Err(de::Error::invalid_type(self.kind(), &visitor))
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            ValueKind::ByteString(s) => match std::str::from_utf8(s) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => Err(de::Error::invalid_value(de::Unexpected::Bytes(s), &visitor))
+            },
+            _ => Err(de::Error::invalid_type(self.kind(), &visitor))
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            ValueKind::ByteString(s) => visitor.visit_borrowed_bytes(s),
+            _ => Err(de::Error::invalid_type(self.kind(), &visitor))
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    // Absent keys are handled by serde, a present key is always Some
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        match self.0 {
+            // Unit variant stored as its name
+            ValueKind::ByteString(_) => visitor.visit_enum(VariantDeserializer { variant: self.0, value: None }),
+            // Other variants stored as a single key dict
+            ValueKind::Dict(entries) if entries.len() == 1 => {
+                let (key, value) = entries.into_iter().next().unwrap();
+                visitor.visit_enum(VariantDeserializer {
+                    variant: ValueKind::ByteString(key),
+                    value: Some(value.kind)
+                })
+            },
+            _ => Err(de::Error::invalid_type(self.kind(), &visitor))
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char unit unit_struct
+        seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct ListAccess<'de>(std::vec::IntoIter<Value<'de>>);
+
+impl<'de> SeqAccess<'de> for ListAccess<'de> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(ValueDeserializer(value.kind)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct DictAccess<'de> {
+    iter: std::vec::IntoIter<(&'de [u8], Value<'de>)>,
+    value: Option<ValueKind<'de>>
+}
+
+impl<'de> MapAccess<'de> for DictAccess<'de> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value.kind);
+                seed.deserialize(ValueDeserializer(ValueKind::ByteString(key))).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let value = self.value.take().ok_or_else(|| <SerdeError as de::Error>::custom("value requested before key"))?;
+        seed.deserialize(ValueDeserializer(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct VariantDeserializer<'de> {
+    variant: ValueKind<'de>,
+    value: Option<ValueKind<'de>>
+}
+
+impl<'de> EnumAccess<'de> for VariantDeserializer<'de> {
+    type Error = SerdeError;
+    type Variant = VariantValue<'de>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantValue<'de>)> {
+        Ok((seed.deserialize(ValueDeserializer(self.variant))?, VariantValue(self.value)))
+    }
+}
+
+struct VariantValue<'de>(Option<ValueKind<'de>>);
+
+impl<'de> VariantAccess<'de> for VariantValue<'de> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.0 {
+            None => Ok(()),
+            Some(value) => <()>::deserialize(ValueDeserializer(value))
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        match self.0 {
+            Some(value) => seed.deserialize(ValueDeserializer(value)),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"newtype variant"))
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_seq(ValueDeserializer(value), visitor),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"tuple variant"))
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_map(ValueDeserializer(value), visitor),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"struct variant"))
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::{collections::BTreeMap, fmt};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{Map, Number, Value as Json};
+use super::{path::format_key, Element, Value, ValueKind};
+
+// Byte strings that are not UTF-8 are written as a single key object, or as a prefixed key
+// when used as a dict key, using one of these markers
+const HEX_MARKER: &str = "$hex";
+const BASE64_MARKER: &str = "$base64";
+// Real keys starting with this get it doubled so they are never read back as a marker,
+// string values need no escaping as only keys are checked for markers
+const ESCAPE: char = '$';
+
+/// How byte strings that are not valid UTF-8 are written in JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BytesFormat {
+    Hex,
+    Base64
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    // Location of the offending JSON value, in the same syntax as bencode paths
+    pub path: String,
+    pub reason: String
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Can not convert JSON at path:{} to bencode, {}", self.path, self.reason)
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+fn bytes_to_json(s: &[u8], format: BytesFormat) -> Json {
+    match std::str::from_utf8(s) {
+        Ok(s) => Json::String(s.to_string()),
+        Err(_) => {
+            let (marker, encoded) = match format {
+                BytesFormat::Hex => (HEX_MARKER, hex::encode(s)),
+                BytesFormat::Base64 => (BASE64_MARKER, STANDARD.encode(s))
+            };
+            let mut mp = Map::new();
+            mp.insert(marker.to_string(), Json::String(encoded));
+            Json::Object(mp)
+        }
+    }
+}
+
+fn key_to_json(key: &[u8], format: BytesFormat) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) if key.starts_with(ESCAPE) => format!("{}{}", ESCAPE, key),
+        Ok(key) => key.to_string(),
+        Err(_) => match format {
+            BytesFormat::Hex => format!("{}:{}", HEX_MARKER, hex::encode(key)),
+            BytesFormat::Base64 => format!("{}:{}", BASE64_MARKER, STANDARD.encode(key))
+        }
+    }
+}
+
+///Convert a decoded value to JSON
+///UTF-8 byte strings become JSON strings, others are written as `{"$hex": "..."}` or `{"$base64": "..."}`
+///Keys that start with `$` are escaped by doubling it
+pub fn to_json(value: &Value, format: BytesFormat) -> Json {
+    match &value.kind {
+        ValueKind::Integer(i) => Json::Number(Number::from(*i)),
+        ValueKind::ByteString(s) => bytes_to_json(s, format),
+        ValueKind::List(l) => Json::Array(l.iter().map(|v| to_json(v, format)).collect()),
+        ValueKind::Dict(entries) => {
+            let mut mp = Map::new();
+            for (key, value) in entries {
+                mp.entry(key_to_json(key, format)).or_insert_with(|| to_json(value, format));
+            }
+            Json::Object(mp)
+        }
+    }
+}
+
+// Undo the marker written by bytes_to_json or key_to_json
+fn decode_marked(marker: &str, encoded: &str) -> Option<Result<Vec<u8>, String>> {
+    match marker {
+        HEX_MARKER => Some(hex::decode(encoded).map_err(|e| e.to_string())),
+        BASE64_MARKER => Some(STANDARD.decode(encoded).map_err(|e| e.to_string())),
+        _ => None
+    }
+}
+
+///Convert JSON written by `to_json` back into an Element
+///Floats, booleans and null have no bencode representation and are rejected
+pub fn from_json(json: &Json) -> Result<Element, JsonError> {
+    from_json_helper(json, "")
+}
+
+fn from_json_helper(json: &Json, path: &str) -> Result<Element, JsonError> {
+
+    let error = |reason: String| JsonError { path: path.to_string(), reason };
+
+    match json {
+        Json::Number(n) => n
+            .as_i64()
+            .map(Element::Integer)
+            .ok_or_else(|| error(format!("{} is not a 64 bit integer", n))),
+        Json::String(s) => Ok(Element::ByteString(s.as_bytes().to_vec())),
+        Json::Array(l) => l
+            .iter()
+            .enumerate()
+            .map(|(i, v)| from_json_helper(v, &format!("{}[{}]", path, i)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Element::List),
+        Json::Object(mp) => {
+
+            // Byte string that is not UTF-8
+            if mp.len() == 1 {
+                if let Some((marker, Json::String(encoded))) = mp.iter().next() {
+                    if let Some(bytes) = decode_marked(marker, encoded) {
+                        return bytes.map(Element::ByteString).map_err(error);
+                    }
+                }
+            }
+
+            let mut dict = BTreeMap::new();
+            for (key, value) in mp {
+                let key_path = if path.is_empty() { format_key(key) } else { format!("{}.{}", path, format_key(key)) };
+                let raw_key = if let Some(escaped) = key.strip_prefix(ESCAPE).filter(|k| k.starts_with(ESCAPE)) {
+                    escaped.as_bytes().to_vec()
+                } else {
+                    match key.split_once(':').and_then(|(marker, encoded)| decode_marked(marker, encoded)) {
+                        Some(bytes) => bytes.map_err(|reason| JsonError { path: key_path.clone(), reason })?,
+                        None => key.as_bytes().to_vec()
+                    }
+                };
+                dict.insert(raw_key, from_json_helper(value, &key_path)?);
+            }
+            Ok(Element::Dict(dict))
+
+        },
+        Json::Bool(_) => Err(error("booleans are not supported".to_string())),
+        Json::Null => Err(error("null is not supported".to_string()))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::bencoded_parser::Bencode;
+    use super::{from_json, to_json, BytesFormat};
+
+    #[test]
+    fn json_round_trip() {
+        let buf = b"d4:infod6:lengthi-7e4:name3:abc6:pieces3:\xff\x00\x01e2:\xfe\xfei1ee".to_vec();
+        let value = Bencode::decode_value(&buf).unwrap();
+
+        let hex = to_json(&value, BytesFormat::Hex);
+        assert_eq!(hex, json!({
+            "info": { "length": -7, "name": "abc", "pieces": { "$hex": "ff0001" } },
+            "$hex:fefe": 1
+        }));
+        let base64 = to_json(&value, BytesFormat::Base64);
+        assert_eq!(base64["info"]["pieces"], json!({ "$base64": "/wAB" }));
+
+        // Both formats convert back to the original bytes
+        assert_eq!(Bencode::encode(&from_json(&hex).unwrap()), buf);
+        assert_eq!(Bencode::encode(&from_json(&base64).unwrap()), buf);
+    }
+
+    #[test]
+    fn marker_like_keys_round_trip() {
+        let buf = b"d4:$hex2:ab8:$hex:zzzd2:$$5:$hex:e1:a4:$hexe".to_vec();
+        let value = Bencode::decode_value(&buf).unwrap();
+
+        let json = to_json(&value, BytesFormat::Hex);
+        assert_eq!(json, json!({ "$$hex": "ab", "$$hex:zzz": { "$$$": "$hex:" }, "a": "$hex" }));
+        assert_eq!(Bencode::encode(&from_json(&json).unwrap()), buf);
+    }
+
+    #[test]
+    fn json_errors() {
+        assert_eq!(from_json(&json!({ "a": [1, 2.5] })).unwrap_err().path, "a[1]");
+        assert_eq!(from_json(&json!({ "a": { "b": null } })).unwrap_err().path, "a.b");
+        assert!(from_json(&json!({ "a": { "$hex": "zz" } })).is_err());
+        assert!(from_json(&json!(true)).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+use std::collections::BTreeMap;
+use super::{Element, Value, ValueKind};
+
+/// One step of a path such as `info.files[3].path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    // Dict key
+    Key(String),
+    // List index
+    Index(usize)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupErrorKind {
+    InvalidPath,
+    Missing,
+    WrongType { expected: &'static str, found: &'static str }
+}
+
+/// Error returned when a path can not be resolved.
+/// `path` is the part of the path that was being resolved when it failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupError {
+    pub path: String,
+    pub kind: LookupErrorKind
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            LookupErrorKind::InvalidPath => write!(f, "Invalid path:{}", self.path),
+            LookupErrorKind::Missing => write!(f, "Missing value at path:{}", self.path),
+            LookupErrorKind::WrongType { expected, found } => write!(f, "Expected {} at path:{}, found {}", expected, self.path, found)
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+///Parse a path
+///Keys are separated by `.` and may contain spaces, list indices are written as `[n]`
+///Keys holding `.`, `[` or `"`, or empty ones, are quoted as in `info."name.utf-8"` with `\"` and `\\` escaped
+pub fn parse_path(path: &str) -> Result<Vec<PathSegment>, LookupError> {
+
+    let invalid = || LookupError { path: path.to_string(), kind: LookupErrorKind::InvalidPath };
+    let mut segments = Vec::new();
+    let mut rest = path;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            // [n]
+            let close = after.find(']').ok_or_else(invalid)?;
+            let index = after[..close].parse().map_err(|_| invalid())?;
+            segments.push(PathSegment::Index(index));
+            rest = &after[close + 1..];
+        }
+        else {
+            // key, a separator is needed between it and a previous segment
+            if !segments.is_empty() {
+                rest = rest.strip_prefix('.').ok_or_else(invalid)?;
+            }
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let (key, len) = parse_quoted(quoted).ok_or_else(invalid)?;
+                segments.push(PathSegment::Key(key));
+                rest = &quoted[len..];
+                continue;
+            }
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            segments.push(PathSegment::Key(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+    }
+
+    Ok(segments)
+
+}
+
+// Key after an opening quote, along with the length up to and including the closing quote
+fn parse_quoted(quoted: &str) -> Option<(String, usize)> {
+
+    let mut key = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((key, i + 1)),
+            '\\' => key.push(chars.next().filter(|(_, c)| *c == '"' || *c == '\\')?.1),
+            c => key.push(c)
+        }
+    }
+    None
+
+}
+
+///Key as written in a path, quoted when it could not be parsed back otherwise
+pub fn format_key(key: &str) -> String {
+    if !key.is_empty() && !key.contains(['.', '[', ']', '"', '\\']) {
+        return key.to_string();
+    }
+    format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Write segments back out in path syntax, used for error messages
+pub(crate) fn format_path(segments: &[PathSegment]) -> String {
+    let mut path = String::new();
+    for segment in segments {
+        match segment {
+            PathSegment::Key(key) => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(&format_key(key));
+            },
+            PathSegment::Index(i) => path.push_str(&format!("[{}]", i))
+        }
+    }
+    path
+}
+
+impl<'a> ValueKind<'a> {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ValueKind::Dict(_) => "dict",
+            ValueKind::Integer(_) => "integer",
+            ValueKind::ByteString(_) => "byte string",
+            ValueKind::List(_) => "list"
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+
+    /// Value at `path` below this value, for example `info.files[3].path`
+    pub fn lookup(&self, path: &str) -> Result<&Value<'a>, LookupError> {
+
+        let segments = parse_path(path)?;
+        let mut curr = self;
+
+        for (i, segment) in segments.iter().enumerate() {
+            let error = |kind| LookupError { path: format_path(&segments[..=i]), kind };
+            curr = match (segment, &curr.kind) {
+                (PathSegment::Key(key), ValueKind::Dict(_)) => curr.get(key.as_bytes()).ok_or_else(|| error(LookupErrorKind::Missing))?,
+                (PathSegment::Index(ind), ValueKind::List(l)) => l.get(*ind).ok_or_else(|| error(LookupErrorKind::Missing))?,
+                (PathSegment::Key(_), kind) => return Err(error(LookupErrorKind::WrongType { expected: "dict", found: kind.type_name() })),
+                (PathSegment::Index(_), kind) => return Err(error(LookupErrorKind::WrongType { expected: "list", found: kind.type_name() }))
+            };
+        }
+
+        Ok(curr)
+
+    }
+
+}
+
+impl Element {
+
+    /// Element at `path` below this element, for example `info.files[3].path`
+    pub fn lookup(&self, path: &str) -> Result<&Element, LookupError> {
+
+        let segments = parse_path(path)?;
+        let mut curr = self;
+
+        for (i, segment) in segments.iter().enumerate() {
+            let error = |kind| LookupError { path: format_path(&segments[..=i]), kind };
+            curr = match (segment, curr) {
+                (PathSegment::Key(key), Element::Dict(mp)) => mp.get(key.as_bytes()).ok_or_else(|| error(LookupErrorKind::Missing))?,
+                (PathSegment::Index(ind), Element::List(l)) => l.get(*ind).ok_or_else(|| error(LookupErrorKind::Missing))?,
+                (PathSegment::Key(_), other) => return Err(error(LookupErrorKind::WrongType { expected: "dict", found: other.type_name() })),
+                (PathSegment::Index(_), other) => return Err(error(LookupErrorKind::WrongType { expected: "list", found: other.type_name() }))
+            };
+        }
+
+        Ok(curr)
+
+    }
+
+    // Look up path and convert the element found, failing with a wrong type error if it can not be
+    fn lookup_as<'e, T>(&'e self, path: &str, expected: &'static str, convert: impl FnOnce(&'e Element) -> Option<T>) -> Result<T, LookupError> {
+        let element = self.lookup(path)?;
+        convert(element).ok_or_else(|| LookupError {
+            path: path.to_string(),
+            kind: LookupErrorKind::WrongType { expected, found: element.type_name() }
+        })
+    }
+
+    pub fn lookup_int(&self, path: &str) -> Result<i64, LookupError> {
+        self.lookup_as(path, "integer", Element::as_int)
+    }
+
+    pub fn lookup_bytes(&self, path: &str) -> Result<&[u8], LookupError> {
+        self.lookup_as(path, "byte string", Element::as_bytes)
+    }
+
+    pub fn lookup_str(&self, path: &str) -> Result<&str, LookupError> {
+        self.lookup_as(path, "UTF-8 string", Element::as_str)
+    }
+
+    pub fn lookup_list(&self, path: &str) -> Result<&[Element], LookupError> {
+        self.lookup_as(path, "list", Element::as_list)
+    }
+
+    pub fn lookup_dict(&self, path: &str) -> Result<&BTreeMap<Vec<u8>, Element>, LookupError> {
+        self.lookup_as(path, "dict", Element::as_dict)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bencoded_parser::{Bencode, ValueKind};
+    use super::{format_path, parse_path, LookupError, LookupErrorKind, PathSegment};
+
+    #[test]
+    fn parse_paths() {
+        assert_eq!(parse_path("info.files[3].path").unwrap(), vec![
+            PathSegment::Key("info".to_string()),
+            PathSegment::Key("files".to_string()),
+            PathSegment::Index(3),
+            PathSegment::Key("path".to_string())
+        ]);
+        assert_eq!(parse_path("info.piece length").unwrap(), vec![
+            PathSegment::Key("info".to_string()),
+            PathSegment::Key("piece length".to_string())
+        ]);
+        assert_eq!(parse_path("[0][1]").unwrap(), vec![PathSegment::Index(0), PathSegment::Index(1)]);
+        assert_eq!(parse_path("").unwrap(), vec![]);
+
+        for path in ["info..name", ".info", "info.", "files[x]", "files[1", "files[0]path", "info.\"name", "info.\"a\"b", "\"\\x\""] {
+            assert_eq!(parse_path(path).unwrap_err().kind, LookupErrorKind::InvalidPath, "{}", path);
+        }
+    }
+
+    #[test]
+    fn quoted_keys() {
+        let segments = parse_path("info.\"name.utf-8\".\"a[\\\"b\\\\\"[0].\"\"").unwrap();
+        assert_eq!(segments, vec![
+            PathSegment::Key("info".to_string()),
+            PathSegment::Key("name.utf-8".to_string()),
+            PathSegment::Key("a[\"b\\".to_string()),
+            PathSegment::Index(0),
+            PathSegment::Key("".to_string())
+        ]);
+        assert_eq!(parse_path(&format_path(&segments)).unwrap(), segments);
+
+        let buf = b"d4:infod4:name1:x10:name.utf-82:okee";
+        let value = Bencode::decode_value(buf).unwrap();
+        assert_eq!(value.lookup("info.\"name.utf-8\"").unwrap().kind, ValueKind::ByteString(b"ok"));
+        assert_eq!(value.lookup("info.\"name\"").unwrap().kind, ValueKind::ByteString(b"x"));
+    }
+
+    #[test]
+    fn lookup_values() {
+        let buf = b"d4:infod5:filesld6:lengthi5e4:pathl1:a1:beee4:name1:xee";
+        let value = Bencode::decode_value(buf).unwrap();
+
+        assert_eq!(value.lookup("info.files[0].length").unwrap().kind, ValueKind::Integer(5));
+        assert_eq!(value.lookup("info.files[0].path[1]").unwrap().kind, ValueKind::ByteString(b"b"));
+        assert_eq!(value.lookup("info.files[1]").unwrap_err(), LookupError {
+            path: "info.files[1]".to_string(),
+            kind: LookupErrorKind::Missing
+        });
+        assert_eq!(value.lookup("info.name.first").unwrap_err(), LookupError {
+            path: "info.name.first".to_string(),
+            kind: LookupErrorKind::WrongType { expected: "dict", found: "byte string" }
+        });
+        assert_eq!(value.lookup("info[0]").unwrap_err().kind, LookupErrorKind::WrongType { expected: "list", found: "dict" });
+    }
+
+    #[test]
+    fn element_lookup() {
+        let element = Bencode::decode_u8(b"d4:infod5:filesld6:lengthi5e4:pathl1:a2:\xff\xfeeee4:name1:x12:piece lengthi16384eee".to_vec()).unwrap();
+
+        assert_eq!(element.lookup_int("info.piece length").unwrap(), 16384);
+        assert_eq!(element.lookup_str("info.name").unwrap(), "x");
+        assert_eq!(element.lookup_list("info.files").unwrap().len(), 1);
+        assert_eq!(element.lookup_bytes("info.files[0].path[1]").unwrap(), b"\xff\xfe");
+        assert_eq!(element.get("info").and_then(|info| info.get("name")).and_then(|name| name.as_str()), Some("x"));
+
+        assert_eq!(element.lookup_str("info.files[0].path[1]").unwrap_err(), LookupError {
+            path: "info.files[0].path[1]".to_string(),
+            kind: LookupErrorKind::WrongType { expected: "UTF-8 string", found: "byte string" }
+        });
+        assert_eq!(element.lookup_int("info.name").unwrap_err().to_string(), "Expected integer at path:info.name, found byte string");
+        assert_eq!(element.lookup_dict("info.files[0].attr").unwrap_err().to_string(), "Missing value at path:info.files[0].attr");
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use serde::ser::{self, Serialize};
+use super::{Bencode, Element, SerdeError};
+
+type Result<T> = std::result::Result<T, SerdeError>;
+
+///Serialize any type implementing Serialize into canonical bencoded bytes
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    Ok(Bencode::encode(&to_element(value)?))
+}
+
+///Serialize any type implementing Serialize into an Element
+pub fn to_element<T: Serialize + ?Sized>(value: &T) -> Result<Element> {
+    value
+        .serialize(ElementSerializer)?
+        .ok_or_else(|| ser::Error::custom("bencode can not represent a missing value"))
+}
+
+// Serializer building an Element tree.
+// None and unit serialize to nothing so that optional struct fields are left out of the dict
+struct ElementSerializer;
+
+fn unsupported<T>(kind: &str) -> Result<T> {
+    Err(ser::Error::custom(format!("bencode does not support {}", kind)))
+}
+
+fn single_key_dict(key: &str, value: Element) -> Element {
+    let mut mp = BTreeMap::new();
+    mp.insert(key.as_bytes().to_vec(), value);
+    Element::Dict(mp)
+}
+
+impl ser::Serializer for ElementSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = DictSerializer;
+
+    // Bencode has no booleans, store them as i0e and i1e
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok> { self.serialize_i64(v as i64) }
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok> { self.serialize_i64(v as i64) }
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok> { self.serialize_i64(v as i64) }
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok> { self.serialize_i64(v as i64) }
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok> { self.serialize_i64(v as i64) }
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok> { self.serialize_i64(v as i64) }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok> {
+        Ok(Some(Element::Integer(v)))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok> {
+        match i64::try_from(v) {
+            Ok(v) => self.serialize_i64(v),
+            Err(_) => unsupported("integers larger than i64::MAX")
+        }
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok> { unsupported("floats") }
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok> { unsupported("floats") }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok> {
+        Ok(Some(Element::ByteString(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    // Unit variants are stored as their name
+    fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str) -> Result<Self::Ok> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    // Variants carrying data are stored as a single key dict
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _variant_index: u32, variant: &'static str, value: &T) -> Result<Self::Ok> {
+        Ok(Some(single_key_dict(variant, to_element(value)?)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Ok(ListSerializer { list: Vec::with_capacity(len.unwrap_or(0)), variant: None })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeTupleVariant> {
+        Ok(ListSerializer { list: Vec::with_capacity(len), variant: Some(variant) })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(DictSerializer { dict: BTreeMap::new(), key: None, variant: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant> {
+        Ok(DictSerializer { dict: BTreeMap::new(), key: None, variant: Some(variant) })
+    }
+}
+
+struct ListSerializer {
+    list: Vec<Element>,
+    variant: Option<&'static str>
+}
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.list.push(to_element(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Element>> {
+        let list = Element::List(self.list);
+        Ok(Some(match self.variant {
+            Some(variant) => single_key_dict(variant, list),
+            None => list
+        }))
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> { self.push(value) }
+    fn end(self) -> Result<Self::Ok> { self.finish() }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> { self.push(value) }
+    fn end(self) -> Result<Self::Ok> { self.finish() }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> { self.push(value) }
+    fn end(self) -> Result<Self::Ok> { self.finish() }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> { self.push(value) }
+    fn end(self) -> Result<Self::Ok> { self.finish() }
+}
+
+struct DictSerializer {
+    dict: BTreeMap<Vec<u8>, Element>,
+    key: Option<Vec<u8>>,
+    variant: Option<&'static str>
+}
+
+impl DictSerializer {
+    // Missing values are left out of the dict entirely
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<()> {
+        if let Some(value) = value.serialize(ElementSerializer)? {
+            self.dict.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Element>> {
+        let dict = Element::Dict(self.dict);
+        Ok(Some(match self.variant {
+            Some(variant) => single_key_dict(variant, dict),
+            None => dict
+        }))
+    }
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    // Dict keys must be byte strings
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        match to_element(key)? {
+            Element::ByteString(key) => {
+                self.key = Some(key);
+                Ok(())
+            },
+            _ => unsupported("dict keys that are not strings")
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take().ok_or_else(|| <SerdeError as ser::Error>::custom("value serialized before key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok> { self.finish() }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok> { self.finish() }
+}
+
+impl ser::SerializeStructVariant for DictSerializer {
+    type Ok = Option<Element>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok> { self.finish() }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+
+/// One bit per piece, packed high bit first as in the bitfield message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: u32
+}
+
+/// Bitfield received from a peer or read from disk that does not fit the torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidBitfield {
+    WrongLength { expected: usize, found: usize },
+    // Bits past the last piece must be zero
+    SpareBits
+}
+
+impl fmt::Display for InvalidBitfield {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidBitfield::WrongLength { expected, found } => write!(f, "Bitfield has {} bytes, expected {}", found, expected),
+            InvalidBitfield::SpareBits => write!(f, "Bitfield has bits set past the last piece")
+        }
+    }
+}
+
+impl std::error::Error for InvalidBitfield {}
+
+impl Bitfield {
+
+    /// Bitfield of len pieces with none set
+    pub fn new(len: u32) -> Bitfield {
+        Bitfield { bytes: vec![0; len.div_ceil(8) as usize], len }
+    }
+
+    ///Decode a bitfield of len pieces as sent on the wire
+    ///It must be exactly as long as needed and have no spare bits set
+    pub fn from_bytes(bytes: &[u8], len: u32) -> Result<Bitfield, InvalidBitfield> {
+
+        let expected = len.div_ceil(8) as usize;
+        if bytes.len() != expected {
+            return Err(InvalidBitfield::WrongLength { expected, found: bytes.len() });
+        }
+
+        let spare = expected as u32 * 8 - len;
+        if spare > 0 && bytes[expected - 1] & ((1 << spare) - 1) != 0 {
+            return Err(InvalidBitfield::SpareBits);
+        }
+
+        Ok(Bitfield { bytes: bytes.to_vec(), len })
+
+    }
+
+    /// Wire encoding, spare bits are zero
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// False for indices past the last piece
+    pub fn get(&self, index: u32) -> bool {
+        index < self.len && self.bytes[(index / 8) as usize] & mask(index) != 0
+    }
+
+    /// Indices past the last piece are ignored
+    pub fn set(&mut self, index: u32, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        let byte = &mut self.bytes[(index / 8) as usize];
+        if value {
+            *byte |= mask(index);
+        }
+        else {
+            *byte &= !mask(index);
+        }
+    }
+
+    /// Number of bits set
+    pub fn count(&self) -> u32 {
+        self.bytes.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indices of the bits set, in order
+    pub fn iter_ones(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len).filter(|&index| self.get(index))
+    }
+
+    /// Bits set in both, for bitfields of the same length
+    pub fn and(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Bits set in self but not in other, for bitfields of the same length
+    pub fn and_not(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    fn combine(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Bitfield {
+        assert_eq!(self.len, other.len, "bitfields of different lengths");
+        let bytes = self.bytes.iter().zip(&other.bytes).map(|(&a, &b)| op(a, b)).collect();
+        Bitfield { bytes, len: self.len }
+    }
+
+}
+
+fn mask(index: u32) -> u8 {
+    0x80 >> (index % 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bitfield, InvalidBitfield};
+
+    #[test]
+    fn bitfield_bits() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+        bitfield.set(10, true);
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+        assert!(bitfield.get(9) && !bitfield.get(8) && !bitfield.get(10));
+        assert_eq!(bitfield.count(), 2);
+        assert_eq!(bitfield.iter_ones().collect::<Vec<_>>(), vec![0, 9]);
+
+        bitfield.set(0, false);
+        assert_eq!(bitfield.iter_ones().collect::<Vec<_>>(), vec![9]);
+        assert!(!bitfield.is_complete());
+        assert!(Bitfield::new(0).is_complete());
+    }
+
+    #[test]
+    fn bitfield_wire() {
+        let theirs = Bitfield::from_bytes(&[0xf0, 0xc0], 10).unwrap();
+        let ours = Bitfield::from_bytes(&[0x30, 0x40], 10).unwrap();
+        assert_eq!(theirs.and(&ours).iter_ones().collect::<Vec<_>>(), vec![2, 3, 9]);
+        assert_eq!(theirs.and_not(&ours).iter_ones().collect::<Vec<_>>(), vec![0, 1, 8]);
+
+        assert_eq!(Bitfield::from_bytes(&[0xff], 10), Err(InvalidBitfield::WrongLength { expected: 2, found: 1 }));
+        assert_eq!(Bitfield::from_bytes(&[0xff, 0xe0], 10), Err(InvalidBitfield::SpareBits));
+        assert!(Bitfield::from_bytes(&[0xff], 8).is_ok());
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::BTreeMap, fs::{self, File}, io, os::unix::{ffi::OsStrExt, fs::{FileExt, MetadataExt}}, path::{Path, PathBuf}
+};
+use rayon::prelude::*;
+use sha1_smol::Sha1;
+use crate::bencoded_parser::{Bencode, Element};
+
+// Piece lengths picked by default stay within these bounds
+const MIN_PIECE_LENGTH: u64 = 1 << 14;
+const MAX_PIECE_LENGTH: u64 = 1 << 24;
+// Number of pieces aimed for when picking a piece length
+const TARGET_PIECES: u64 = 1500;
+
+/// Everything about a new torrent except its files
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // Tiers of tracker urls, the first url is also written as announce
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Seconds since the unix epoch
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    pub source: Option<String>,
+    // url-list web seeds (BEP 19)
+    pub web_seeds: Vec<String>,
+    // Picked from the total size when not given, must be a power of two of at least 16 KiB
+    pub piece_length: Option<u64>
+}
+
+///Piece length for a torrent of the given total size
+///Power of two giving about 1500 pieces, between 16 KiB and 16 MiB
+pub fn piece_length_for(total: u64) -> u64 {
+    (total / TARGET_PIECES).next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+///Create a torrent for a file or directory
+///Files of a directory are added in sorted path order, returns the canonical bencoding of the torrent
+pub fn create_torrent(path: &Path, opts: &CreateOptions) -> io::Result<Vec<u8>> {
+    Ok(Bencode::encode(&create_element(path, opts)?))
+}
+
+///Create a torrent for a file or directory as an Element
+pub fn create_element(path: &Path, opts: &CreateOptions) -> io::Result<Element> {
+
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+
+    let name = path
+        .canonicalize()?
+        .file_name()
+        .ok_or_else(|| invalid("path has no name"))?
+        .as_bytes()
+        .to_vec();
+
+    // Files with their path relative to the torrent root
+    let single = fs::metadata(path)?.is_file();
+    let mut files = Vec::new();
+    if single {
+        files.push((path.to_path_buf(), PathBuf::new(), fs::metadata(path)?.len()));
+    }
+    else {
+        // Leaving out what can not be read would give a torrent missing data, so that fails
+        let mut skipped = Vec::new();
+        walk(path, PathBuf::new(), &mut files, &mut skipped);
+        if let Some((path, e)) = skipped.into_iter().next() {
+            return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)));
+        }
+        if files.is_empty() {
+            return Err(invalid("directory has no files"));
+        }
+    }
+
+    let total = files.iter().map(|(_, _, len)| len).sum();
+    let piece_length = match opts.piece_length {
+        Some(len) if len < MIN_PIECE_LENGTH || !len.is_power_of_two() => return Err(invalid("piece length must be a power of two of at least 16 KiB")),
+        Some(len) => len,
+        None => piece_length_for(total)
+    };
+
+    let handles = files
+        .iter()
+        .map(|(path, _, len)| Ok((File::open(path)?, *len)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let pieces = hash_pieces(&handles, total, piece_length)?;
+
+    // Info dict
+    let mut info = BTreeMap::new();
+    info.insert(b"name".to_vec(), Element::ByteString(name));
+    info.insert(b"piece length".to_vec(), Element::Integer(piece_length as i64));
+    info.insert(b"pieces".to_vec(), Element::ByteString(pieces));
+    if single {
+        info.insert(b"length".to_vec(), Element::Integer(total as i64));
+    }
+    else {
+        let list = files
+            .iter()
+            .map(|(_, relative, len)| {
+                let path = relative
+                    .iter()
+                    .map(|component| Element::ByteString(component.as_bytes().to_vec()))
+                    .collect();
+                Element::Dict(BTreeMap::from([
+                    (b"length".to_vec(), Element::Integer(*len as i64)),
+                    (b"path".to_vec(), Element::List(path))
+                ]))
+            })
+            .collect();
+        info.insert(b"files".to_vec(), Element::List(list));
+    }
+    if opts.private {
+        info.insert(b"private".to_vec(), Element::Integer(1));
+    }
+    if let Some(source) = &opts.source {
+        info.insert(b"source".to_vec(), string(source));
+    }
+
+    // Torrent dict
+    let mut torrent = BTreeMap::new();
+    torrent.insert(b"info".to_vec(), Element::Dict(info));
+
+    let tiers: Vec<&Vec<String>> = opts.announce_list.iter().filter(|tier| !tier.is_empty()).collect();
+    if let Some(first) = tiers.first() {
+        torrent.insert(b"announce".to_vec(), string(&first[0]));
+    }
+    if tiers.iter().map(|tier| tier.len()).sum::<usize>() > 1 {
+        let list = tiers.iter().map(|tier| Element::List(tier.iter().map(|url| string(url)).collect())).collect();
+        torrent.insert(b"announce-list".to_vec(), Element::List(list));
+    }
+    if let Some(comment) = &opts.comment {
+        torrent.insert(b"comment".to_vec(), string(comment));
+    }
+    if let Some(created_by) = &opts.created_by {
+        torrent.insert(b"created by".to_vec(), string(created_by));
+    }
+    if let Some(date) = opts.creation_date {
+        torrent.insert(b"creation date".to_vec(), Element::Integer(date));
+    }
+    if !opts.web_seeds.is_empty() {
+        torrent.insert(b"url-list".to_vec(), Element::List(opts.web_seeds.iter().map(|url| string(url)).collect()));
+    }
+
+    Ok(Element::Dict(torrent))
+
+}
+
+fn string(s: &str) -> Element {
+    Element::ByteString(s.as_bytes().to_vec())
+}
+
+// Collect files below dir in sorted order, symlinks are followed
+// Entries that can not be read are left out and collected in skipped with the reason,
+// as are links to a directory we are already inside of, which would never end
+pub(crate) fn walk(dir: &Path, relative: PathBuf, files: &mut Vec<(PathBuf, PathBuf, u64)>, skipped: &mut Vec<(PathBuf, io::Error)>) {
+    match fs::metadata(dir) {
+        Ok(metadata) => walk_inside(dir, relative, files, skipped, &mut vec![(metadata.dev(), metadata.ino())]),
+        Err(e) => skipped.push((dir.to_path_buf(), e))
+    }
+}
+
+// ancestors holds the device and inode of dir and every directory above it
+fn walk_inside(dir: &Path, relative: PathBuf, files: &mut Vec<(PathBuf, PathBuf, u64)>, skipped: &mut Vec<(PathBuf, io::Error)>, ancestors: &mut Vec<(u64, u64)>) {
+
+    let mut entries = match fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+        Ok(entries) => entries,
+        Err(e) => return skipped.push((dir.to_path_buf(), e))
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let relative = relative.join(entry.file_name());
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                skipped.push((path, e));
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            let id = (metadata.dev(), metadata.ino());
+            if ancestors.contains(&id) {
+                skipped.push((path, io::Error::other("links to a directory it is inside of")));
+                continue;
+            }
+            ancestors.push(id);
+            walk_inside(&path, relative, files, skipped, ancestors);
+            ancestors.pop();
+        }
+        else {
+            files.push((path, relative, metadata.len()));
+        }
+    }
+
+}
+
+// SHA-1 of every piece, pieces span file boundaries and are hashed in parallel
+fn hash_pieces(files: &[(File, u64)], total: u64, piece_length: u64) -> io::Result<Vec<u8>> {
+
+    let count = total.div_ceil(piece_length);
+
+    let hashes = (0..count)
+        .into_par_iter()
+        .map(|i| {
+            let offset = i * piece_length;
+            let mut buf = vec![0u8; piece_length.min(total - offset) as usize];
+            read_at(files, offset, &mut buf)?;
+
+            let mut hasher = Sha1::new();
+            hasher.update(&buf);
+            Ok(hasher.digest().bytes())
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok(hashes.concat())
+
+}
+
+// Fill buf with the bytes at offset of the files laid end to end
+fn read_at(files: &[(File, u64)], mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+
+    for (file, len) in files {
+        if buf.is_empty() {
+            break;
+        }
+        if offset >= *len {
+            offset -= len;
+            continue;
+        }
+        let n = buf.len().min((len - offset) as usize);
+        file.read_exact_at(&mut buf[..n], offset)?;
+        buf = &mut buf[n..];
+        offset = 0;
+    }
+
+    if buf.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "files changed while hashing"))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink, path::PathBuf};
+    use sha1_smol::Sha1;
+    use crate::bencoded_parser::Bencode;
+    use super::{create_torrent, piece_length_for, walk, CreateOptions};
+
+    #[test]
+    fn piece_lengths() {
+        assert_eq!(piece_length_for(0), 1 << 14);
+        assert_eq!(piece_length_for(100 << 20), 1 << 17);
+        assert_eq!(piece_length_for(1 << 40), 1 << 24);
+    }
+
+    #[test]
+    fn create_directory_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("data");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let (a, b) = (vec![1u8; 20000], vec![2u8; 30000]);
+        fs::write(root.join("sub").join("b.bin"), &b).unwrap();
+        fs::write(root.join("a.bin"), &a).unwrap();
+
+        let opts = CreateOptions {
+            announce_list: vec![vec!["http://one/announce".to_string()], vec!["http://two/announce".to_string()]],
+            comment: Some("test".to_string()),
+            creation_date: Some(1700000000),
+            private: true,
+            source: Some("team".to_string()),
+            web_seeds: vec!["http://seed/".to_string()],
+            ..CreateOptions::default()
+        };
+        let buf = create_torrent(&root, &opts).unwrap();
+
+        // Output is canonical, so strict decoding accepts it
+        let element = Bencode::decode_u8(buf).unwrap();
+        assert_eq!(element.lookup_str("announce").unwrap(), "http://one/announce");
+        assert_eq!(element.lookup_str("announce-list[1][0]").unwrap(), "http://two/announce");
+        assert_eq!(element.lookup_int("creation date").unwrap(), 1700000000);
+        assert_eq!(element.lookup_str("url-list[0]").unwrap(), "http://seed/");
+        assert_eq!(element.lookup_str("info.name").unwrap(), "data");
+        assert_eq!(element.lookup_int("info.private").unwrap(), 1);
+        assert_eq!(element.lookup_str("info.source").unwrap(), "team");
+        assert_eq!(element.lookup_str("info.files[1].path[0]").unwrap(), "sub");
+        assert_eq!(element.lookup_int("info.files[1].length").unwrap(), 30000);
+
+        // Pieces cross the boundary between the two files
+        let data = [a, b].concat();
+        let pieces = element.lookup_bytes("info.pieces").unwrap();
+        assert_eq!(pieces.len(), 4 * 20);
+        for (i, chunk) in data.chunks(1 << 14).enumerate() {
+            let mut hasher = Sha1::new();
+            hasher.update(chunk);
+            assert_eq!(&pieces[i * 20..(i + 1) * 20], hasher.digest().bytes());
+        }
+    }
+
+    #[test]
+    fn create_single_file_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, b"hello").unwrap();
+
+        let element = Bencode::decode_u8(create_torrent(&path, &CreateOptions::default()).unwrap()).unwrap();
+        assert_eq!(element.lookup_int("info.length").unwrap(), 5);
+        assert!(element.get("announce").is_none());
+        assert!(element.lookup("info.files").is_err());
+
+        let opts = CreateOptions { piece_length: Some(1000), ..CreateOptions::default() };
+        assert!(create_torrent(&path, &opts).is_err());
+        assert!(create_torrent(dir.path(), &CreateOptions::default()).is_ok());
+        assert!(create_torrent(&dir.path().join("empty"), &CreateOptions::default()).is_err());
+    }
+
+    #[test]
+    fn walk_symlink_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/file"), b"data").unwrap();
+        symlink(dir.path().join("a"), dir.path().join("a/b/up")).unwrap();
+        // Two links to the same directory are not a cycle
+        symlink(dir.path().join("a/b"), dir.path().join("a/same")).unwrap();
+        symlink(dir.path().join("missing"), dir.path().join("dangling")).unwrap();
+
+        let (mut files, mut skipped) = (Vec::new(), Vec::new());
+        walk(dir.path(), PathBuf::new(), &mut files, &mut skipped);
+        let relative: Vec<_> = files.iter().map(|(_, relative, _)| relative.clone()).collect();
+        assert_eq!(relative, vec![PathBuf::from("a/b/file"), PathBuf::from("a/same/file")]);
+        let skipped: Vec<_> = skipped.iter().map(|(path, _)| path.strip_prefix(dir.path()).unwrap().to_path_buf()).collect();
+        assert_eq!(skipped, vec![PathBuf::from("a/b/up"), PathBuf::from("a/same/up"), PathBuf::from("dangling")]);
+
+        // A torrent can not leave them out
+        assert!(create_torrent(dir.path(), &CreateOptions::default()).is_err());
+    }
+}
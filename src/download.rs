@@ -1,26 +1,51 @@
 use std::{
-    collections::{HashSet, LinkedList}, fs::File, io::{Write, stdout}, net::{Ipv4Addr, SocketAddrV4}, os::unix::fs::FileExt, sync::Arc, time::Duration
+    collections::{HashSet, LinkedList}, fs::File, io::{self, Write, stdout}, net::{Ipv4Addr, SocketAddrV4}, os::unix::fs::FileExt, sync::Arc, time::Duration
 };
 use crossterm::{QueueableCommand, cursor, terminal, ExecutableCommand};
-use sha1_smol::Sha1;
 use tokio::{
     io::{AsyncWriteExt, AsyncReadExt},
     net::TcpStream,
-    sync::Mutex,
+    sync::{watch, Mutex},
+    task::JoinSet,
     time::{timeout, sleep, self}
 };
 use byteorder::{BigEndian, ReadBytesExt};
 use crate::{
-    torrent_parser::{Torrent, Piece}, 
+    torrent_parser::{Metainfo, Torrent, Piece}, 
     message::{HandshakeMsg, Message}, 
-    helpers::{self, BLOCK_SIZE, CONN_LIMIT, on_whole_msg}
+    metadata::{ExtendedHandshake, MetadataDownload, MetadataMessage, EXTENDED_HANDSHAKE_ID, EXTENDED_ID, UT_METADATA_ID},
+    bitfield::Bitfield,
+    helpers::{BLOCK_SIZE, CONN_LIMIT, on_whole_msg},
+    layout::{FileLayout, Segment}
 };
 
-pub async fn download_file(torrent: Torrent, file_ref: Arc<Vec<(File, u64)>>) {    
+///Download from the peers found until the torrent is complete or stop is set
+///A stopped download aborts its peer tasks and waits for them, nothing is written to the files once it returns
+pub async fn download_file(torrent: Torrent, file_ref: Arc<TorrentFiles>, mut stop: watch::Receiver<bool>) {
+
+    let mut tasks = JoinSet::new();
+    // A dropped sender never stops the download
+    let stopped = tokio::select! {
+        _ = connect_peers(&torrent, &file_ref, &mut tasks) => false,
+        Ok(_) = stop.wait_for(|&stop| stop) => true
+    };
+
+    if stopped {
+        tasks.abort_all();
+    }
+    while let Some(result) = tasks.join_next().await {
+        if let Err(e) = result {
+            assert!(e.is_cancelled(), "{}", e);
+        }
+    }
+
+}
+
+// Start a task for every new peer until the torrent is complete
+async fn connect_peers(torrent: &Torrent, file_ref: &Arc<TorrentFiles>, tasks: &mut JoinSet<()>) {
 
-    let mut handles = vec![];
     loop {
-        if *(torrent.piece_left.lock().await) == 0 {
+        if torrent.have.lock().await.is_complete() {
             break;
         }
 
@@ -36,72 +61,136 @@ pub async fn download_file(torrent: Torrent, file_ref: Arc<Vec<(File, u64)>>) {
             let file_ref = file_ref.clone();
             let down_ref = torrent.downloaded.clone();
             let conn_ref = torrent.connections.clone();
-            let hashes = torrent.piece_hashes.clone();
-            let left = torrent.piece_left.clone();
+            let have = torrent.have.clone();
+            let metainfo = torrent.metainfo.clone();
+            let peer_id = torrent.peer_id;
 
             if (*(conn_ref.lock().await)).contains(&peer) {
                 continue;
             }
 
-            let h = tokio::spawn( async move{
+            tasks.spawn( async move{
 
-                let stream = connect(peer, torrent.info_hash, torrent.peer_id).await;
-                if let Some(stream) = stream {
+                let stream = connect(peer, &metainfo.info_hashes(), peer_id).await;
+                if let Some((stream, extensions)) = stream {
                     {
                         let mut connections = conn_ref.lock().await;
                         (*connections).insert(peer);
                     }
-                    handle_connection(stream, freq_ref, file_ref, down_ref, hashes, left).await;
+                    handle_connection(stream, extensions, freq_ref, file_ref, down_ref, have, metainfo).await;
                     {
                         let mut connections = conn_ref.lock().await;
                         (*connections).remove(&peer);
                     }
                 }
-                else {
-                    return;
-                }
             });
-
-            handles.push(h);
         }
         
         sleep(time::Duration::from_secs(5)).await;
     }
 
-    for handle in handles {
-        handle.await.unwrap();
+}
+
+///Fetch the info dict of a torrent from peers using ut_metadata (BEP 9)
+///Peers are tried one at a time, the info dict returned matches the info hash
+pub async fn fetch_metadata(peers: &[(u32,u16)], info_hash: [u8; 20], peer_id: [u8; 20]) -> Option<Vec<u8>> {
+
+    for peer in peers {
+        let metadata = timeout(Duration::from_secs(30), fetch_metadata_from(*peer, info_hash, peer_id)).await.ok().flatten();
+        if metadata.is_some() {
+            return metadata;
+        }
+    }
+
+    None
+
+}
+
+async fn fetch_metadata_from(peer: (u32,u16), info_hash: [u8; 20], peer_id: [u8; 20]) -> Option<Vec<u8>> {
+
+    let (mut stream, extensions) = connect(peer, &[info_hash], peer_id).await?;
+    if !extensions {
+        return None;
+    }
+    stream.write_all(&Message::build_extended(EXTENDED_HANDSHAKE_ID, &ExtendedHandshake::new(None).to_bytes())).await.ok()?;
+
+    let mut download: Option<MetadataDownload> = None;
+
+    loop {
+
+        let len = get_length(&mut stream).await?;
+        let msg = on_whole_msg(&mut stream, len).await?;
+
+        // Everything but extension messages is ignored
+        if msg.len() < 2 || msg[0] != EXTENDED_ID {
+            continue;
+        }
+
+        match msg[1] {
+            EXTENDED_HANDSHAKE_ID => {
+
+                // Request every piece once the peer tells us the size
+                let handshake = ExtendedHandshake::parse(&msg[2..])?;
+                let id = handshake.ut_metadata()?;
+                let pending = MetadataDownload::new(info_hash, handshake.metadata_size?)?;
+                for piece in pending.missing() {
+                    stream.write_all(&Message::build_extended(id, &MetadataMessage::request(piece))).await.ok()?;
+                }
+                download = Some(pending);
+
+            },
+            UT_METADATA_ID => {
+
+                let (header, data) = MetadataMessage::parse(&msg[2..])?;
+                let pending = download.as_mut()?;
+                if header.is_reject() || (header.is_data() && !pending.receive(header.piece, data)) {
+                    return None;
+                }
+                if pending.is_complete() {
+                    return download?.finish();
+                }
+
+            },
+            _ => {}
+        }
+
     }
 
 }
 
-async fn connect(peer: (u32,u16), info_hash: [u8; 20], peer_id: [u8; 20]) -> Option<TcpStream> {
+// Connect and handshake, also returns whether the peer supports the extension protocol
+// Hybrid torrents have two info hashes, each is tried in turn until the peer accepts one
+async fn connect(peer: (u32,u16), info_hashes: &[[u8; 20]], peer_id: [u8; 20]) -> Option<(TcpStream, bool)> {
 
     let socket = SocketAddrV4::new(Ipv4Addr::from(peer.0),peer.1);
-    let stream = timeout(tokio::time::Duration::from_secs(2),TcpStream::connect(socket)).await.ok()?.ok()?;
 
-    handshake(stream, info_hash, peer_id).await
+    for info_hash in info_hashes {
+        let stream = timeout(tokio::time::Duration::from_secs(2),TcpStream::connect(socket)).await.ok()?.ok()?;
+        if let Some(connected) = handshake(stream, *info_hash, peer_id).await {
+            return Some(connected);
+        }
+    }
+
+    None
 
 }
 
-async fn handshake(mut stream: TcpStream, info_hash: [u8; 20], peer_id: [u8;20]) -> Option<TcpStream> {
+async fn handshake(mut stream: TcpStream, info_hash: [u8; 20], peer_id: [u8;20]) -> Option<(TcpStream, bool)> {
 
     // Get handshake msg
-    let mut handshake_msg = HandshakeMsg::build_msg(info_hash, peer_id);
+    let handshake_msg = HandshakeMsg::build_msg(info_hash, peer_id);
 
     // Write handshake message to stream
-    stream.write(&mut handshake_msg).await.unwrap();
+    stream.write_all(&handshake_msg).await.ok()?;
 
     // Read handshake response
-    let mut buf = Vec::new();
-    let bytes_read = timeout(tokio::time::Duration::from_secs(2),stream.read_buf(&mut buf)).await.ok()?.ok()?;
+    let mut buf = [0; 68];
+    timeout(tokio::time::Duration::from_secs(2),stream.read_exact(&mut buf)).await.ok()?.ok()?;
     
-    // Check whether response handshake or not
-    if bytes_read == 64 && String::from_utf8_lossy(&buf[..=19])[1..] == "BitTorrent protocol".to_string() {
-        // Read waste 4 bytes
-        let mut tmp = [0; 4];
-        let _res = timeout(tokio::time::Duration::from_secs(2),stream.read_exact(&mut tmp)).await;
+    // Check whether response handshake for the same torrent or not
+    if buf[0] == 19 && &buf[1..20] == b"BitTorrent protocol" && buf[28..48] == info_hash {
         // Handle Torrent further from here
-        Some(stream)
+        Some((stream, HandshakeMsg::supports_extensions(&buf[20..28])))
     }
     else {
         None
@@ -109,27 +198,44 @@ async fn handshake(mut stream: TcpStream, info_hash: [u8; 20], peer_id: [u8;20])
 
 }
 
-async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>>>, file: Arc<Vec<(File, u64)>>, down_ref: Arc<Mutex<u64>>, hashes: Arc<Vec<Vec<u8>>>, piece_left: Arc<Mutex<u16>>) {
+async fn handle_connection(mut stream: TcpStream, extensions: bool, freq_ref: Arc<Mutex<Vec<Piece>>>, file: Arc<TorrentFiles>, down_ref: Arc<Mutex<u64>>, have: Arc<Mutex<Bitfield>>, metainfo: Arc<Metainfo>) {
 
-    let mut bitfield = vec![false; (*(freq_ref.lock().await)).len()];
+    // Pieces the peer has
+    let mut bitfield = Bitfield::new(metainfo.piece_count());
     let mut choke = true;
     let mut requested: LinkedList<u32> = LinkedList::new();
-    let mut piece_req: Option<usize> = None;
+    let mut piece_req: Option<u32> = None;
+    let mut peer_ut_metadata: Option<u8> = None;
+
+    // Bitfield has to be the first message, it is only sent when we have something
+    let ours = have.lock().await.clone();
+    if ours.count() > 0 {
+        stream.write_all(&Message::build_bitfield(&ours)).await.ok();
+    }
+
+    // Tell peers that support it we can serve metadata
+    if extensions {
+        let handshake = ExtendedHandshake::new(Some(metainfo.info_bytes.len())).to_bytes();
+        stream.write_all(&Message::build_extended(EXTENDED_HANDSHAKE_ID, &handshake)).await.ok();
+    }
 
     loop {
         
 
         // Read length
-        let mut msg;
+        let msg;
         if let Some(len) = get_length(&mut stream).await {
             msg = on_whole_msg(&mut stream, len).await.unwrap();
         }
         else { 
             if !requested.is_empty() {
 
+                // Blocks may be gone already if the piece failed verification meanwhile
                 let mut freq = freq_ref.lock().await;
                 for begin in requested {
-                    (*freq)[piece_req.unwrap()].blocks[begin as usize].is_req = false;
+                    if let Some(block) = (*freq)[piece_req.unwrap() as usize].blocks.get_mut(begin as usize) {
+                        block.is_req = false;
+                    }
                 }
 
             }
@@ -139,7 +245,7 @@ async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>
         
         // Read id of message
         let mut id = None;
-        if msg.len() >= 1 { id = Some(msg[0]); }
+        if !msg.is_empty() { id = Some(msg[0]); }
 
         match id {
             None => {
@@ -165,29 +271,25 @@ async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>
             Some(4) => {
 
                 // have
-                let piece_index = ReadBytesExt::read_u32::<BigEndian>(&mut msg.as_mut_slice()[1..].as_ref()).unwrap();
-                if !bitfield[piece_index as usize] {
+                let Ok(piece_index) = ReadBytesExt::read_u32::<BigEndian>(&mut msg[1..].as_ref()) else {
+                    return;
+                };
+                if piece_index < bitfield.len() && !bitfield.get(piece_index) {
                     (*(freq_ref.lock().await))[piece_index as usize].ref_no += 1;
-                    bitfield[piece_index as usize] = true;
+                    bitfield.set(piece_index, true);
                 }
 
             },
             Some(5) => {
 
-                //bitfield
+                //bitfield, peers sending one that does not fit the torrent are dropped
+                let Ok(received) = Bitfield::from_bytes(&msg[1..], bitfield.len()) else {
+                    return;
+                };
                 let mut freq_arr = freq_ref.lock().await;
-                for i in 1..msg.len() {
-                    for (j, val) in helpers::u8_to_bin(msg[i as usize]).iter().enumerate() {
-
-                        if !val { continue; }
-                        let ind = (i as usize -1)*8 + j;
-                        if ind >= bitfield.len() {
-                            break;
-                        }
-                        bitfield[ind] = *val;
-                        (*freq_arr)[ind].ref_no += 1;
-
-                    }
+                for ind in received.and_not(&bitfield).iter_ones() {
+                    (*freq_arr)[ind as usize].ref_no += 1;
+                    bitfield.set(ind, true);
                 }
 
             },
@@ -203,7 +305,7 @@ async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>
                 let mut donwloaded = down_ref.lock().await;
                 *donwloaded += (msg.len() - 9) as u64;
 
-                let begin = write_to_file(msg, file.clone(), freq_ref.clone()).await;
+                let begin = write_to_file(msg, file.clone()).await;
                 
                 for (i, el) in requested.iter().enumerate() {
                     if *el == begin {
@@ -216,29 +318,15 @@ async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>
                 
                 if requested.is_empty() {
 
-                    let piece_length;
-                    let offset;
-                    {
-                        let freq = freq_ref.lock().await;
-                        piece_length = (*freq)[piece_req.unwrap()].length;
-                        offset = (*freq)[piece_req.unwrap()].blocks[0].offset;
-                    }
-                    
-                    if !verify_piece(piece_length, offset, file.clone(), &(*hashes)[piece_req.unwrap()]) {
-                        let mut freq = freq_ref.lock().await;
-
-                        for block in &mut (*freq)[piece_req.unwrap()].blocks {
-                            block.is_req = false;
-                        }
+                    if !verify_piece(&file, piece_req.unwrap(), &metainfo) {
+                        // Pick it again from scratch
+                        freq_ref.lock().await[piece_req.unwrap() as usize].blocks.clear();
                     }
                     else {
 
-                        let mut freq = freq_ref.lock().await;
-                        (*freq)[piece_req.unwrap()].completed = true;
-                        
-                        let mut left = piece_left.lock().await;
-                        *left -= 1;
-                        
+                        have.lock().await.set(piece_req.unwrap(), true);
+                        // Free the blocks of a finished piece
+                        freq_ref.lock().await[piece_req.unwrap() as usize].blocks = Vec::new();
                     }
 
                 }
@@ -250,6 +338,24 @@ async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>
             Some(9) => {
                 // port
             },
+            Some(20) => {
+
+                // extension protocol
+                match msg.get(1) {
+                    Some(&EXTENDED_HANDSHAKE_ID) => {
+                        peer_ut_metadata = ExtendedHandshake::parse(&msg[2..]).and_then(|handshake| handshake.ut_metadata());
+                    },
+                    Some(&UT_METADATA_ID) => {
+                        // Serve our metadata to peers that joined from a magnet link
+                        let answer = MetadataMessage::parse(&msg[2..]).and_then(|(req, _)| req.answer(Some(&metainfo.info_bytes)));
+                        if let (Some(answer), Some(id)) = (answer, peer_ut_metadata) {
+                            stream.write_all(&Message::build_extended(id, &answer)).await.ok();
+                        }
+                    },
+                    _ => {}
+                }
+
+            },
             _ => {
                 return;
             }
@@ -257,8 +363,9 @@ async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>
 
         if !choke && requested.is_empty() {
 
-            (requested, piece_req) = make_request(freq_ref.lock().await, &mut stream, &bitfield).await;
-            if piece_req == None {return;}
+            let wanted = bitfield.and_not(&*have.lock().await);
+            (requested, piece_req) = make_request(freq_ref.lock().await, &mut stream, &wanted).await;
+            if piece_req.is_none() {return;}
 
         }
 
@@ -266,51 +373,21 @@ async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>
 
 }
 
-pub fn verify_piece(piece_length: u64, offset: u64, file: Arc<Vec<(File,u64)>>, hash: &Vec<u8>) -> bool {
-
-    let mut buf = vec![0u8; piece_length as usize];
+// Read a piece from the files and check it against the hashes of the torrent
+pub fn verify_piece(files: &TorrentFiles, piece: u32, metainfo: &Metainfo) -> bool {
 
-    // Reading file at different locations
-    let mut ind: usize = 0;
-    let mut length: u64 = 0;
-    while length <= offset  {
-        length += (*file)[ind].1;
-        ind += 1;
-    }
-    ind -= 1;
-
-    // Read file
-    let mut read = 0;
-    while read != piece_length as usize && ind < (*file).len() {
-        let res = (*file)[ind].0.read_at(&mut buf[read..], offset);
-
-        // Return false if error in reading
-        match res {
-            Ok(bytes) => { read += bytes; },
-            Err(_) => {
-                return false; 
-            }
-        }
-
-        ind += 1;
-    }
-
-    if read != piece_length as usize {
+    let Some(length) = files.layout.piece_size(piece) else {
         return false;
-    }
+    };
+    let mut buf = vec![0u8; length as usize];
 
-    // Generate hash
-    let mut hasher = Sha1::new();
-    hasher.update(&buf);
-
-    // Validate hash
-    if (*hash) == hasher.digest().bytes() {
-        return true;
-    }
-    else {
+    // Return false if error in reading, including files that are still too short
+    if files.read_at(piece, 0, &mut buf).is_err() {
         return false;
     }
 
+    metainfo.verify_piece(piece, &buf)
+
 }
 
 async fn get_length(stream: &mut TcpStream) -> Option<u32> {
@@ -321,42 +398,30 @@ async fn get_length(stream: &mut TcpStream) -> Option<u32> {
     Some(ReadBytesExt::read_u32::<BigEndian>(&mut buf.as_ref()).unwrap())
 }
 
-async fn make_request(mut freq_arr: tokio::sync::MutexGuard<'_, Vec<Piece>>, stream: &mut TcpStream, bitfield: &Vec<bool> ) -> (LinkedList<u32>, Option<usize>) {
+async fn make_request(mut freq_arr: tokio::sync::MutexGuard<'_, Vec<Piece>>, stream: &mut TcpStream, wanted: &Bitfield) -> (LinkedList<u32>, Option<u32>) {
 
     let mut to_req = None;
-    let mut mn = u16::MAX;
-
-    // Find piece with minimum nodes
-    for (i, piece) in (*freq_arr).iter_mut().enumerate() {
-        if bitfield[i] && piece.ref_no < mn && piece.completed == false {
-
-            for block in piece.blocks.iter() {
-
-                if block.is_req == false {
-
-                    to_req = Some(i);
-                    mn = piece.ref_no;
-                    break;
-
-                }
-            }
-            
+    let mut mn = u32::MAX;
+
+    // Find piece with minimum nodes among those the peer has and we do not
+    for i in wanted.iter_ones() {
+        let piece = &(*freq_arr)[i as usize];
+        if piece.ref_no < mn && piece.has_free_blocks() {
+            to_req = Some(i);
+            mn = piece.ref_no;
         }
-
     }
 
     let mut req = LinkedList::new();
     
-    if to_req != None {
-        
-        let ind = to_req.unwrap();
+    if let Some(ind) = to_req {
 
-        for (j, block) in (*freq_arr)[ind].blocks.iter_mut().enumerate() {
-            if block.is_req == false {
+        for (j, block) in (*freq_arr)[ind as usize].blocks_mut().iter_mut().enumerate() {
+            if !block.is_req {
                 block.is_req = true;
-                let res = stream.write(&Message::build_request(ind as u32, (j as u32)*BLOCK_SIZE, block.length as u32)).await;
+                let res = stream.write_all(&Message::build_request(ind, (j as u32)*BLOCK_SIZE, block.length as u32)).await;
 
-                if let Err(_) = res {
+                if res.is_err() {
                     return (req, None);
                 }
 
@@ -368,36 +433,69 @@ async fn make_request(mut freq_arr: tokio::sync::MutexGuard<'_, Vec<Piece>>, str
     (req, to_req)
 }
 
-async fn write_to_file(mut msg: Vec<u8>, file: Arc<Vec<(File, u64)>>, freq_ref: Arc<Mutex<Vec<Piece>>>) -> u32 {
+async fn write_to_file(msg: Vec<u8>, file: Arc<TorrentFiles>) -> u32 {
 
     // piece
-    let buf = &mut msg.as_mut_slice()[1..].as_ref();
+    let buf = &mut msg[1..].as_ref();
     let index = ReadBytesExt::read_u32::<BigEndian>(buf).unwrap();
-    let mut begin = ReadBytesExt::read_u32::<BigEndian>(buf).unwrap();
-    begin /= BLOCK_SIZE;
+    let begin = ReadBytesExt::read_u32::<BigEndian>(buf).unwrap();
 
-    let offset = (*freq_ref.lock().await)[index as usize].blocks[begin as usize].offset;
+    // Blocks that do not fit the piece are dropped and the piece fails verification
+    file.write_at(index, begin as u64, &msg[9..]).ok();
+    begin / BLOCK_SIZE
+}
+
+/// Files of a torrent opened for reading and writing
+/// Padding files and symlinks hold no data and are None
+pub struct TorrentFiles {
+    pub layout: FileLayout,
+    pub files: Vec<Option<File>>
+}
+
+impl TorrentFiles {
+
+    ///Read the bytes at begin of a piece
+    ///Padding files read as zeros
+    pub fn read_at(&self, piece: u32, begin: u64, buf: &mut [u8]) -> io::Result<()> {
+
+        let mut buf = buf;
+        for segment in self.segments(piece, begin, buf.len())? {
+            let (part, rest) = buf.split_at_mut(segment.length as usize);
+            match &self.files[segment.file] {
+                Some(file) => file.read_exact_at(part, segment.offset)?,
+                None => part.fill(0)
+            }
+            buf = rest;
+        }
+        Ok(())
 
-    // Writing to file at different locations
-    let mut ind: usize = 0;
-    let mut length: u64 = 0;
-    while length <= offset  {
-        length += (*file)[ind].1;
-        ind += 1;
     }
-    ind -= 1;
-    let available = (*file)[ind].1 - offset;
-    if available < (msg.len()-9) as u64 {
-        ((*file)[ind]).0.write_at(&msg[9..(9 + available) as usize], offset).unwrap();
-        ((*file)[ind+1]).0.write_at(&msg[(9 + available) as usize ..], offset).unwrap();
+
+    ///Write data at begin of a piece
+    ///Bytes that fall in padding files are dropped
+    pub fn write_at(&self, piece: u32, begin: u64, data: &[u8]) -> io::Result<()> {
+
+        let mut data = data;
+        for segment in self.segments(piece, begin, data.len())? {
+            let (part, rest) = data.split_at(segment.length as usize);
+            if let Some(file) = &self.files[segment.file] {
+                file.write_all_at(part, segment.offset)?;
+            }
+            data = rest;
+        }
+        Ok(())
+
     }
-    else {
-        ((*file)[ind]).0.write_at(&msg[9..], offset).unwrap();
+
+    fn segments(&self, piece: u32, begin: u64, length: usize) -> io::Result<Vec<Segment>> {
+        self.layout
+            .segments(piece, begin, length as u64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "range past the end of the piece"))
     }
-    begin
+
 }
 
-pub async fn download_print(downloaded: Arc<Mutex<u64>>, connections: Arc<Mutex<HashSet<(u32,u16)>>>, piece_left: Arc<Mutex<u16>>) {
+pub async fn download_print(downloaded: Arc<Mutex<u64>>, connections: Arc<Mutex<HashSet<(u32,u16)>>>, have: Arc<Mutex<Bitfield>>) {
     let mut stdout = stdout();
 
     stdout.execute(cursor::Hide).unwrap();
@@ -412,14 +510,15 @@ pub async fn download_print(downloaded: Arc<Mutex<u64>>, connections: Arc<Mutex<
         {
             now = *(downloaded.lock().await);
             connection = (*(connections.lock().await)).len();
-            left = *(piece_left.lock().await);
+            let have = have.lock().await;
+            left = have.len() - have.count();
         }
 
         if left == 0 {
             break;
         }
 
-        let tot = (now as f64) / (1048756 as f64);
+        let tot = (now as f64) / 1048756_f64;
         let speed = ((now - last) as f64) / ((1048756*3) as f64);
         
         stdout.write_all(format!("\rDownloaded: {:.2} MB\nSpeed: {:.2} MB/s\nConnections: {}/{}\nPieces Left: {}", tot, speed, connection, CONN_LIMIT, left).as_bytes()).unwrap();
@@ -432,4 +531,122 @@ pub async fn download_print(downloaded: Arc<Mutex<u64>>, connections: Arc<Mutex<
     stdout.execute(cursor::Show).unwrap();
 
     println!("Done!");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, OpenOptions}, net::Ipv4Addr, sync::Arc, time::Duration};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::{oneshot, watch}, time::sleep};
+    use crate::{
+        bitfield::Bitfield, create::{create_torrent, CreateOptions}, layout::FileLayout, message::{HandshakeMsg, Message}, torrent_parser::{Metainfo, Torrent}
+    };
+    use super::{download_file, TorrentFiles};
+
+    // Next request sent by the client, other messages are skipped
+    async fn next_request(stream: &mut TcpStream) -> (u32, u32, u32) {
+        loop {
+            let len = stream.read_u32().await.unwrap();
+            let mut msg = vec![0u8; len as usize];
+            stream.read_exact(&mut msg).await.unwrap();
+            if msg.first() == Some(&6) {
+                let field = |i: usize| u32::from_be_bytes(msg[1 + 4 * i..5 + 4 * i].try_into().unwrap());
+                return (field(0), field(1), field(2));
+            }
+        }
+    }
+
+    fn piece_msg(index: u32, begin: u32, data: &[u8]) -> Vec<u8> {
+        let mut msg = (9 + data.len() as u32).to_be_bytes().to_vec();
+        msg.push(7);
+        msg.extend(index.to_be_bytes());
+        msg.extend(begin.to_be_bytes());
+        msg.extend(data);
+        msg
+    }
+
+    #[test]
+    fn padding_reads_as_zeros() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |name: &str| OpenOptions::new().read(true).write(true).create(true).truncate(true).open(dir.path().join(name)).unwrap();
+        let files = TorrentFiles {
+            layout: FileLayout::new(vec![3, 0, 2, 4], 9),
+            files: vec![Some(open("a")), Some(open("empty")), None, Some(open("b"))]
+        };
+
+        // Block crossing both files and the padding between them
+        files.write_at(0, 1, b"12345678").unwrap();
+        let mut buf = [9u8; 9];
+        files.read_at(0, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"\x0012\x00\x005678");
+        assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), b"5678");
+
+        assert!(files.write_at(0, 8, b"xy").is_err());
+        assert!(files.read_at(1, 0, &mut [0u8; 1]).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn no_writes_after_stop() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..32768u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.path().join("source"), &data).unwrap();
+        let opts = CreateOptions {
+            announce_list: vec![vec!["http://tracker/announce".to_string()]],
+            piece_length: Some(16384),
+            ..CreateOptions::default()
+        };
+        let metainfo = Metainfo::from_bytes(&create_torrent(&dir.path().join("source"), &opts).unwrap()).unwrap();
+        let info_hash = metainfo.info_hash;
+
+        let target = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(dir.path().join("target")).unwrap();
+        let files = Arc::new(TorrentFiles { layout: FileLayout::from_metainfo(&metainfo), files: vec![Some(target)] });
+
+        // Peer with both pieces that only answers the request for the second one once the download is stopped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (requested, wait_requested) = oneshot::channel();
+        let (answer, wait_answer) = oneshot::channel::<()>();
+        let peer_data = data.clone();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&HandshakeMsg::build_msg(info_hash, [2; 20])).await.unwrap();
+
+            let mut all = Bitfield::new(2);
+            all.set(0, true);
+            all.set(1, true);
+            stream.write_all(&Message::build_bitfield(&all)).await.unwrap();
+            stream.write_all(&Message::build_unchoke()).await.unwrap();
+
+            let (index, begin, length) = next_request(&mut stream).await;
+            let start = (index * 16384 + begin) as usize;
+            stream.write_all(&piece_msg(index, begin, &peer_data[start..start + length as usize])).await.unwrap();
+
+            let (index, begin, length) = next_request(&mut stream).await;
+            requested.send(()).unwrap();
+            wait_answer.await.unwrap();
+            let start = (index * 16384 + begin) as usize;
+            stream.write_all(&piece_msg(index, begin, &peer_data[start..start + length as usize])).await.ok();
+        });
+
+        let torrent = Torrent::new(metainfo);
+        torrent.peer_list.lock().await.push_back((u32::from(Ipv4Addr::LOCALHOST), port));
+        let have = torrent.have.clone();
+        let (stop, stopped) = watch::channel(false);
+        let downloading = tokio::spawn(download_file(torrent, files.clone(), stopped));
+
+        wait_requested.await.unwrap();
+        stop.send(true).unwrap();
+        downloading.await.unwrap();
+
+        // The second piece arrives after the stop and is never written
+        answer.send(()).unwrap();
+        peer.await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        let written = fs::read(dir.path().join("target")).unwrap();
+        assert_eq!(written[..16384], data[..16384]);
+        assert!(written[16384..].iter().all(|&byte| byte == 0));
+        assert_eq!(have.lock().await.count(), 1);
+    }
+}
@@ -10,9 +10,9 @@ pub fn u8_to_hex(mut val: u8) -> String {
     let mut ans = String::new();
     for _ in 0..2 {
         ans.push(if val%16 <= 9 {
-            ('0' as u8+ val%16) as char
+            (b'0' + val%16) as char
         } else {
-            ('A' as u8 + (val%16 - 10)) as char
+            (b'A' + (val%16 - 10)) as char
         });
         val /= 16;
     }
@@ -25,10 +25,7 @@ pub fn u8_to_url(arr: [u8; 20]) -> String {
     let mut ans = String::new();
 
     for byte in arr {
-        if (byte >= ('0' as u8) && byte <= ('9' as u8)) 
-        || (byte >= ('A' as u8) && byte <= ('Z' as u8)) 
-        || (byte >= ('a' as u8) && byte <= ('z' as u8))
-        || byte == '.' as u8 || byte == '-' as u8 || byte == '_' as u8 || byte == '~' as u8  {
+        if byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-' || byte == b'_' || byte == b'~' {
             ans.push(byte as char);
         }
         else {
@@ -49,16 +46,21 @@ pub fn u8_to_bin(n: u8) -> Vec<bool> {
     s
 }
 
-// Generate a random peer id
-pub fn gen_random_id() -> [u8; 20] {
-
-    let mut buf: [u8; 20] = [0;20];
-    for i in  0..20 {
-        buf[i] = rand::random();
+// Human readable size in binary units
+pub fn format_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
     }
+    if unit == 0 { format!("{} B", size) } else { format!("{:.2} {}", value, units[unit]) }
+}
 
-    buf
-
+// Generate a random peer id
+pub fn gen_random_id() -> [u8; 20] {
+    rand::random()
 }
 
 // Function which returns message from Tcp Stream only on getting message of entire length provided as input.
@@ -79,7 +81,7 @@ pub async fn on_whole_msg(stream: &mut TcpStream, len: u32) -> Option<Vec<u8>> {
 
 #[cfg(test)]
 mod tests {
-    use crate::helpers::{u8_to_bin, u8_to_url};
+    use crate::helpers::{format_size, u8_to_bin, u8_to_url};
 
     #[test]
     fn u8_to_bin_test() {
@@ -95,4 +97,11 @@ mod tests {
         assert_eq!(u8_to_url(arr), "%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A");
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn format_size_test() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.50 KiB");
+        assert_eq!(format_size(5 << 30), "5.00 GiB");
+    }
+}
@@ -0,0 +1,207 @@
+use std::ops::Range;
+use crate::torrent_parser::Metainfo;
+
+/// Part of a range of torrent data that lies in one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    // Index of the file in the torrent
+    pub file: usize,
+    // Offset within the file
+    pub offset: u64,
+    pub length: u64
+}
+
+/// Files of a torrent laid end to end, maps piece ranges to the files holding them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLayout {
+    piece_length: u64,
+    // Offset of the first byte of each file in the torrent
+    starts: Vec<u64>,
+    lengths: Vec<u64>,
+    total: u64
+}
+
+impl FileLayout {
+
+    pub fn new(lengths: Vec<u64>, piece_length: u64) -> FileLayout {
+
+        let mut starts = Vec::with_capacity(lengths.len());
+        let mut total = 0;
+        for length in &lengths {
+            starts.push(total);
+            total += length;
+        }
+
+        FileLayout { piece_length, starts, lengths, total }
+
+    }
+
+    /// Layout of the files of a torrent, a single file torrent has one file
+    pub fn from_metainfo(metainfo: &Metainfo) -> FileLayout {
+        let lengths = match &metainfo.files {
+            Some(files) => files.iter().map(|file| file.length).collect(),
+            None => vec![metainfo.length]
+        };
+        FileLayout::new(lengths, metainfo.piece_length)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn piece_count(&self) -> u64 {
+        self.total.div_ceil(self.piece_length)
+    }
+
+    /// Offset of the first byte of a file in the torrent
+    pub fn file_start(&self, file: usize) -> u64 {
+        self.starts[file]
+    }
+
+    pub fn file_length(&self, file: usize) -> u64 {
+        self.lengths[file]
+    }
+
+    /// Pieces lying entirely inside a file, their hashes depend on no other file
+    pub fn pieces_within(&self, file: usize) -> Range<u32> {
+
+        let (start, end) = (self.starts[file], self.starts[file] + self.lengths[file]);
+        let first = start.div_ceil(self.piece_length);
+        // The short last piece of the torrent ends with the last file
+        let last = if end == self.total { end.div_ceil(self.piece_length) } else { end / self.piece_length };
+
+        first as u32..last.max(first) as u32
+
+    }
+
+    /// Pieces holding any byte of a file
+    pub fn pieces_overlapping(&self, file: usize) -> Range<u32> {
+        let (start, end) = (self.starts[file], self.starts[file] + self.lengths[file]);
+        if start == end {
+            return 0..0;
+        }
+        (start / self.piece_length) as u32..end.div_ceil(self.piece_length) as u32
+    }
+
+    /// Length of a piece, only the last one may be shorter
+    pub fn piece_size(&self, piece: u32) -> Option<u64> {
+        let start = (piece as u64).checked_mul(self.piece_length).filter(|&start| start < self.total)?;
+        Some(self.piece_length.min(self.total - start))
+    }
+
+    ///Segments holding length bytes at begin of a piece
+    ///None if the range does not fit in the piece
+    pub fn segments(&self, piece: u32, begin: u64, length: u64) -> Option<Vec<Segment>> {
+
+        let size = self.piece_size(piece)?;
+        if begin.checked_add(length)? > size {
+            return None;
+        }
+        self.range(piece as u64 * self.piece_length + begin, length)
+
+    }
+
+    ///Segments holding length bytes at offset of the files laid end to end
+    ///Zero-length files never appear, None if the range goes past the last file
+    pub fn range(&self, offset: u64, length: u64) -> Option<Vec<Segment>> {
+
+        if offset.checked_add(length)? > self.total {
+            return None;
+        }
+
+        // Last file starting at or before offset, files after it start past offset
+        let first = self.starts.partition_point(|&start| start <= offset).saturating_sub(1);
+
+        let mut segments = Vec::new();
+        let (mut offset, mut left) = (offset, length);
+        for file in first..self.lengths.len() {
+            if left == 0 {
+                break;
+            }
+            let end = self.starts[file] + self.lengths[file];
+            if offset >= end {
+                continue;
+            }
+            let n = left.min(end - offset);
+            segments.push(Segment { file, offset: offset - self.starts[file], length: n });
+            offset += n;
+            left -= n;
+        }
+
+        Some(segments)
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileLayout, Segment};
+
+    fn segment(file: usize, offset: u64, length: u64) -> Segment {
+        Segment { file, offset, length }
+    }
+
+    #[test]
+    fn piece_segments() {
+        // Zero-length files at the start, in the middle and at the end
+        let layout = FileLayout::new(vec![0, 5, 0, 3, 10, 0], 4);
+        assert_eq!(layout.total_length(), 18);
+        assert_eq!(layout.piece_count(), 5);
+        assert_eq!(layout.piece_size(4), Some(2));
+        assert_eq!(layout.piece_size(5), None);
+
+        assert_eq!(layout.segments(0, 0, 4), Some(vec![segment(1, 0, 4)]));
+        assert_eq!(layout.segments(1, 0, 4), Some(vec![segment(1, 4, 1), segment(3, 0, 3)]));
+        assert_eq!(layout.segments(2, 1, 3), Some(vec![segment(4, 1, 3)]));
+        assert_eq!(layout.segments(4, 0, 2), Some(vec![segment(4, 8, 2)]));
+        assert_eq!(layout.segments(4, 2, 0), Some(vec![]));
+
+        // One range across every file
+        assert_eq!(layout.range(0, 18), Some(vec![segment(1, 0, 5), segment(3, 0, 3), segment(4, 0, 10)]));
+
+        // Past the end of a piece or of the torrent
+        assert_eq!(layout.segments(0, 2, 3), None);
+        assert_eq!(layout.segments(4, 0, 4), None);
+        assert_eq!(layout.segments(0, u64::MAX, 2), None);
+        assert_eq!(layout.range(17, 2), None);
+
+        // Piece 4 is short and ends the torrent, zero-length files hold no piece
+        assert_eq!(layout.pieces_within(1), 0..1);
+        assert_eq!(layout.pieces_within(4), 2..5);
+        assert_eq!(layout.pieces_within(3), 2..2);
+        assert_eq!(layout.pieces_within(0), 0..0);
+        assert_eq!(layout.pieces_overlapping(1), 0..2);
+        assert_eq!(layout.pieces_overlapping(3), 1..2);
+        assert_eq!(layout.pieces_overlapping(0), 0..0);
+    }
+
+    #[test]
+    fn multi_terabyte_layout() {
+        // 8 TiB in 16 MiB pieces, the second file starts two bytes into piece 196608
+        let layout = FileLayout::new(vec![(3 << 40) + 2, (5 << 40) - 2], 16 << 20);
+        assert_eq!(layout.piece_count(), 524288);
+        assert_eq!(layout.piece_size(524287), Some(16 << 20));
+        assert_eq!(layout.piece_size(524288), None);
+
+        assert_eq!(layout.segments(196608, 0, 8), Some(vec![segment(0, 3 << 40, 2), segment(1, 0, 6)]));
+        assert_eq!(layout.segments(524287, 0, 16384), Some(vec![segment(1, (5 << 40) - (16 << 20) - 2, 16384)]));
+        assert_eq!(layout.segments(u32::MAX, 0, 1), None);
+    }
+
+    #[test]
+    fn empty_layout() {
+        let layout = FileLayout::new(vec![0, 0], 16384);
+        assert_eq!(layout.piece_count(), 0);
+        assert_eq!(layout.piece_size(0), None);
+        assert_eq!(layout.range(0, 0), Some(vec![]));
+    }
+}
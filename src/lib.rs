@@ -1,6 +1,16 @@
 pub mod bencoded_parser;
+pub mod create;
 pub mod tracker;
 pub mod torrent_parser;
 pub mod download;
 pub mod message;
-pub mod helpers;
\ No newline at end of file
+pub mod helpers;
+pub mod magnet;
+pub mod metadata;
+pub mod sanitize;
+pub mod merkle;
+pub mod layout;
+pub mod bitfield;
+pub mod resume;
+pub mod reuse;
+pub mod update;
@@ -0,0 +1,184 @@
+use std::{fmt, net::SocketAddrV4};
+use url::{form_urlencoded, Url};
+
+/// Parsed `magnet:?xt=urn:btih:...` link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    // dn
+    pub name: Option<String>,
+    // tr, in the order given
+    pub trackers: Vec<String>,
+    // x.pe, peers to connect to directly as host:port
+    pub peers: Vec<String>,
+    // ws
+    pub web_seeds: Vec<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidMagnet {
+    NotMagnet,
+    MissingInfoHash,
+    InvalidInfoHash(String)
+}
+
+impl fmt::Display for InvalidMagnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidMagnet::NotMagnet => write!(f, "Not a magnet link"),
+            InvalidMagnet::MissingInfoHash => write!(f, "Magnet link has no urn:btih info hash"),
+            InvalidMagnet::InvalidInfoHash(hash) => write!(f, "Invalid info hash in magnet link:{}", hash)
+        }
+    }
+}
+
+impl std::error::Error for InvalidMagnet {}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", hex::encode(self.info_hash))?;
+        let params = self.name
+            .iter()
+            .map(|name| ("dn", name))
+            .chain(self.trackers.iter().map(|url| ("tr", url)))
+            .chain(self.peers.iter().map(|peer| ("x.pe", peer)))
+            .chain(self.web_seeds.iter().map(|url| ("ws", url)));
+        for (key, value) in params {
+            write!(f, "&{}={}", key, form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>())?;
+        }
+        Ok(())
+    }
+}
+
+impl Magnet {
+
+    ///Parse magnet link
+    ///The info hash can be 40 hex or 32 base32 characters, parameters other than xt, dn, tr, x.pe and ws are ignored
+    pub fn parse(uri: &str) -> Result<Magnet, InvalidMagnet> {
+
+        let url = Url::parse(uri).map_err(|_| InvalidMagnet::NotMagnet)?;
+        if url.scheme() != "magnet" {
+            return Err(InvalidMagnet::NotMagnet);
+        }
+
+        let mut info_hash = None;
+        let mut magnet = Magnet { info_hash: [0; 20], name: None, trackers: Vec::new(), peers: Vec::new(), web_seeds: Vec::new() };
+
+        // Values are percent decoded by query_pairs, numbered keys such as tr.1 are accepted too
+        for (key, value) in url.query_pairs() {
+            let key = key.split_once('.').filter(|(_, n)| n.parse::<u32>().is_ok()).map_or(&*key, |(k, _)| k);
+            match key {
+                "xt" => {
+                    // Other hash types such as urn:btmh are skipped
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                },
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "x.pe" => magnet.peers.push(value.into_owned()),
+                "ws" => magnet.web_seeds.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        magnet.info_hash = info_hash.ok_or(InvalidMagnet::MissingInfoHash)?;
+        Ok(magnet)
+
+    }
+
+    /// Direct peers that are IPv4 addresses, in the form used by the peer list
+    pub fn ipv4_peers(&self) -> Vec<(u32,u16)> {
+        self.peers
+            .iter()
+            .filter_map(|peer| peer.parse::<SocketAddrV4>().ok())
+            .map(|addr| (u32::from(*addr.ip()), addr.port()))
+            .collect()
+    }
+
+}
+
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], InvalidMagnet> {
+
+    let invalid = || InvalidMagnet::InvalidInfoHash(hash.to_string());
+
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|_| invalid())?,
+        32 => base32_decode(hash).ok_or_else(invalid)?,
+        _ => return Err(invalid())
+    };
+
+    bytes.try_into().map_err(|_| invalid())
+
+}
+
+// Decode RFC 4648 base32 without padding, case insensitive
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+
+    let mut ret = Vec::new();
+    let (mut buffer, mut bits) = (0u64, 0);
+
+    for c in s.bytes() {
+        let val = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None
+        };
+        buffer = (buffer << 5) | val as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(ret)
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InvalidMagnet, Magnet};
+
+    const HASH: [u8; 20] = [
+        0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9,
+        0xf5, 0x19, 0xb3, 0x35, 0xaa, 0x7c, 0x13, 0x67, 0xa8, 0x8a
+    ];
+
+    #[test]
+    fn parse_hex_magnet() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Some+File%20Name\
+            &tr=udp%3A%2F%2Ftracker.one%3A80&tr.1=http%3A%2F%2Ftracker.two%2Fannounce\
+            &x.pe=10.0.0.1:6881&x.pe=peer.host:51413&ws=http%3A%2F%2Fseed.test%2Ffile&foo=bar").unwrap();
+
+        assert_eq!(magnet.info_hash, HASH);
+        assert_eq!(magnet.name.as_deref(), Some("Some File Name"));
+        assert_eq!(magnet.trackers, vec!["udp://tracker.one:80", "http://tracker.two/announce"]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881", "peer.host:51413"]);
+        assert_eq!(magnet.ipv4_peers(), vec![(0x0a000001, 6881)]);
+        assert_eq!(magnet.web_seeds, vec!["http://seed.test/file"]);
+
+        // Written back out it parses to the same link
+        assert_eq!(Magnet::parse(&magnet.to_string()).unwrap(), magnet);
+    }
+
+    #[test]
+    fn parse_base32_magnet() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(magnet.info_hash, HASH);
+        assert_eq!(magnet.name, None);
+        assert!(magnet.trackers.is_empty());
+
+        // Lower case base32 is accepted
+        assert_eq!(Magnet::parse("magnet:?xt=urn:btih:yex6dqdlxisuvhoj6um3gnnkpqjwpkek").unwrap().info_hash, HASH);
+    }
+
+    #[test]
+    fn invalid_magnets() {
+        assert_eq!(Magnet::parse("http://example.com/?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"), Err(InvalidMagnet::NotMagnet));
+        assert_eq!(Magnet::parse("not a link"), Err(InvalidMagnet::NotMagnet));
+        assert_eq!(Magnet::parse("magnet:?dn=name"), Err(InvalidMagnet::MissingInfoHash));
+        assert_eq!(Magnet::parse("magnet:?xt=urn:btih:c12fe1"), Err(InvalidMagnet::InvalidInfoHash("c12fe1".to_string())));
+        assert!(Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1").is_err());
+    }
+}
@@ -1,92 +1,475 @@
-use std::{fs::{File, self, OpenOptions},env, sync::Arc, path::PathBuf};
+use std::{fs::{File, self, OpenOptions}, os::unix::fs::{symlink, PermissionsExt},env, fmt, process, sync::Arc, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 use r_torrent::{
-    torrent_parser::{Torrent, Piece},
-    download,
-    tracker::get_peers
+    bencoded_parser::{json::{self, BytesFormat}, Bencode, DecodeOptions},
+    create::{self, CreateOptions},
+    bitfield::Bitfield,
+    torrent_parser::{self, Metainfo, Torrent, TorrentFile, Piece},
+    download::{self, TorrentFiles},
+    helpers,
+    layout::FileLayout,
+    magnet::Magnet,
+    resume,
+    reuse,
+    sanitize,
+    tracker::{self, get_peers, AnnounceTiers},
+    update
 };
-use tokio::{sync::Mutex, time};
+use tokio::{sync::{watch, Mutex}, time};
 
 #[tokio::main]
 async fn main() {
+
+    if env::args().nth(1).as_deref() == Some("bencode") {
+        bencode_command(env::args().skip(2).collect());
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("create") {
+        create_command(env::args().skip(2).collect());
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("info") {
+        info_command(env::args().skip(2).collect());
+        return;
+    }
     
     // Open file and get decoded and info hash
+    let usage = "usage: cargo run source_torrent|magnet_link destination_folder [--reuse existing_download]... [--stop-old] [--no-updates]";
     let mut args = env::args();
     if args.len() < 3 {
-        panic!("usage: cargo run source_torrent destination_folder");
+        panic!("{}", usage);
     }
     args.next();
     
     let dir = env::current_dir().unwrap();
+    let source = args.next().unwrap();
 
-    // Open .torrent file
-    let source_dir = dir
-        .join(args
-                .next()
-                .unwrap());
-    let mut file = File::open(source_dir).unwrap();
-    
+    // All info mentioned in torrent file, or fetched from peers for a magnet link
+    let torrent = if source.starts_with("magnet:") {
+        let magnet = Magnet::parse(&source).unwrap_or_else(|e| exit_with(e));
+        magnet_torrent(magnet).await
+    }
+    else {
+        // Open .torrent file
+        let source_dir = dir.join(source);
+        let mut file = File::open(source_dir).unwrap_or_else(|e| exit_with(e));
+        Torrent::new(Metainfo::from_reader(&mut file).unwrap_or_else(|e| exit_with(e)))
+    };
+    for warning in &torrent.metainfo.warnings {
+        println!("Warning: {}", warning);
+    }
+    let root = dir.join(args.next().unwrap());
 
-    // All info mentioned in torrent file
-    let mut torrent = Torrent::parse_decoded(&mut file).await.unwrap(); 
-    
-    // Initialize Destination file
-    let destination_dir = dir
-            .join(args
-                .next()
-                .unwrap())
-            .join(&torrent.name.to_owned());
+    // Earlier downloads to take matching data from (BEP 38), and whether a newer version replaces this one (BEP 39)
+    // A torrent with an update-url is watched for newer versions after it completes, so only --no-updates makes it exit then
+    let mut reuse_dirs = Vec::new();
+    let mut stop_old = false;
+    let mut follow_updates = true;
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--reuse" => reuse_dirs.push(dir.join(args.next().unwrap_or_else(|| panic!("{}", usage)))),
+            "--stop-old" => stop_old = true,
+            "--no-updates" => follow_updates = false,
+            _ => panic!("{}", usage)
+        }
+    }
+
+    let destination_dir = destination(&root, &torrent.metainfo);
+    run(torrent, root, destination_dir, reuse_dirs, stop_old, follow_updates).await;
+
+}
+
+// Names come from the torrent so keep them inside the destination folder
+fn destination(root: &Path, metainfo: &Metainfo) -> PathBuf {
+    let name = sanitize::sanitize_path(std::slice::from_ref(&metainfo.name)).unwrap_or_else(|e| exit_with(e));
+    for change in &name.changes {
+        println!("Warning: {}", change);
+    }
+    root.join(name.path)
+}
+
+// Download a torrent into destination_dir, and its newer versions when it has an update-url and follow_updates is set
+async fn run(torrent: Torrent, root: PathBuf, destination_dir: PathBuf, reuse_dirs: Vec<PathBuf>, stop_old: bool, follow_updates: bool) {
+
+    let metainfo = torrent.metainfo.clone();
+
+    // Pieces completed in an earlier run are kept next to the download
+    let mut resume_path = destination_dir.clone().into_os_string();
+    resume_path.push(".resume");
+    let resume_path = PathBuf::from(resume_path);
     
     // Create a file vector and pass it to download function
     let mut file_vec = Vec::new();
     
     // if multiple files
-    if torrent.file_list != None {
+    if let Some(files) = &metainfo.files {
 
         // Create dir based on destination dir
         fs::create_dir_all(&destination_dir).unwrap();
 
-        // Create files inside that dir
-        for (path, size) in torrent.file_list.unwrap() {
-            let file_path = destination_dir.join(path);
-            file_vec.push((open_file(file_path), size));
+        // Create files inside that dir, padding files and symlinks hold no data
+        let paths: Vec<_> = files.iter().map(|file| file.path.clone()).collect();
+        let paths = sanitize::sanitize_paths(&paths).unwrap_or_else(|e| exit_with(e));
+
+        for (file, path) in files.iter().zip(paths) {
+            for change in &path.changes {
+                println!("Warning: {}", change);
+            }
+            file_vec.push(create_file(&destination_dir, &path.path, file));
         }
-        torrent.file_list = None;
     }
     else {
-        file_vec.push(( open_file(destination_dir), torrent.length ));
+        file_vec.push(Some(open_file(destination_dir.clone())));
     }
 
 
     // Distribute torrent info
-    let (announce_url, announce_list) = (torrent.announce_url, torrent.announce_list);
-    (torrent.announce_url, torrent.announce_list) = (None, None);
+    let tiers = AnnounceTiers::new(metainfo.announce.clone(), metainfo.announce_list.clone());
     
-    let file_vec = Arc::new(file_vec);
-    verify_file(torrent.piece_freq.clone(), file_vec.clone(), torrent.piece_hashes.clone(), torrent.downloaded.clone(), torrent.piece_left.clone()).await;
+    let file_vec = Arc::new(TorrentFiles { layout: FileLayout::from_metainfo(&metainfo), files: file_vec });
+
+    if !reuse_dirs.is_empty() {
+        let (candidates, skipped) = reuse::find_candidates(&reuse_dirs);
+        let stats = reuse::reuse_data(&metainfo, &file_vec, &candidates).unwrap_or_else(|e| exit_with(e));
+        for skipped in skipped.iter().chain(&stats.skipped) {
+            println!("Warning: {}", skipped);
+        }
+        println!("Reused {} files with {} matching pieces", stats.files, stats.pieces);
+    }
+    else if let Some(hint) = reuse::similar_hint(&metainfo) {
+        println!("{}", hint);
+    }
+    // Files changed since the resume data was saved are hashed again
+    let resumed = resume::stamps(&file_vec).ok().and_then(|stamps| resume::load(&resume_path, metainfo.info_hash, metainfo.piece_count(), &stamps));
+    verify_file(torrent.piece_freq.clone(), file_vec.clone(), metainfo.clone(), torrent.downloaded.clone(), torrent.have.clone(), resumed).await;
     
     // Get peers
     let h1 = get_peers(
-        torrent.info_hash.clone(),
-        torrent.length.clone(),
-        torrent.peer_id.clone(),
-        announce_url,
+        metainfo.info_hashes(),
+        metainfo.length,
+        metainfo.piece_length,
+        torrent.peer_id,
+        tiers,
         torrent.peer_list.clone(),
-        announce_list,
         torrent.connections.clone(),
         torrent.downloaded.clone(),
-        torrent.piece_left.clone()
+        torrent.have.clone()
     );
 
 
     // Display function for downloading
-    let h2 = download::download_print(torrent.downloaded.clone(), torrent.connections.clone(), torrent.piece_left.clone());
+    let h2 = download::download_print(torrent.downloaded.clone(), torrent.connections.clone(), torrent.have.clone());
+
+    // Keep resume data up to date
+    let h4 = save_resume(resume_path, metainfo.info_hash, torrent.have.clone(), file_vec.clone());
 
 
     // Download torrent
-    let h3 = download::download_file(torrent, file_vec);
+    let (stop, stopped) = watch::channel(false);
+    let h3 = download::download_file(torrent, file_vec, stopped);
 
+    // Peers, progress and resume data end with a complete download, a stopped one leaves them behind
+    let downloading = async {
+        let others = async {
+            tokio::join!(h1, h2, h4);
+        };
+        tokio::pin!(h3, others);
+        tokio::select! {
+            _ = &mut others => (&mut h3).await,
+            _ = &mut h3 => if !*stop.borrow() {
+                others.await;
+            }
+        }
+    };
+    let Some(update_url) = metainfo.update_url.as_ref().filter(|_| follow_updates) else {
+        downloading.await;
+        return;
+    };
 
-    tokio::join!(h1, h2, h3);
+    // Say why we keep running once the download is complete
+    let downloading = async {
+        downloading.await;
+        if !*stop.borrow() {
+            println!("Download complete, watching {} for newer versions, pass --no-updates to exit when done", update_url);
+        }
+    };
+
+    // Newer versions published on the update-url (BEP 39)
+    let updated = update::wait_for_update(&metainfo, update::POLL_INTERVAL, |e| eprintln!("Warning: {}", e));
+    tokio::pin!(updated);
+    if stop_old {
+        // The old download has stopped writing by the time the new one starts
+        let (_, latest) = tokio::join!(downloading, async {
+            let latest = (&mut updated).await;
+            let _ = stop.send(true);
+            latest
+        });
+        start_update(latest, root, destination_dir, stop_old).await;
+    }
+    else {
+        tokio::join!(downloading, async {
+            let latest = (&mut updated).await;
+            start_update(latest, root, destination_dir, stop_old).await;
+        });
+    }
+
+}
+
+// Start the newer version of a torrent, taking what it shares with the old one from its files
+async fn start_update(latest: Metainfo, root: PathBuf, old_dir: PathBuf, stop_old: bool) {
+
+    println!("Found newer version of {}, info hash:{}", latest.name, hex::encode(latest.info_hash));
+
+    // Peer tasks of the old version may still be writing, so never share its files
+    let mut destination_dir = destination(&root, &latest);
+    if destination_dir == old_dir {
+        let mut dir = destination_dir.into_os_string();
+        dir.push(format!("-{}", &hex::encode(latest.info_hash)[..8]));
+        destination_dir = PathBuf::from(dir);
+    }
+
+    // Keep the new torrent so the download can be started again
+    let mut torrent_path = destination_dir.clone().into_os_string();
+    torrent_path.push(".torrent");
+    if let Err(e) = fs::write(&torrent_path, latest.to_bytes()) {
+        eprintln!("Warning: could not save updated torrent, {}", e);
+    }
+
+    Box::pin(run(Torrent::new(latest), root, destination_dir, vec![old_dir], stop_old, true)).await;
+
+}
+
+// Only the info hash is known for a magnet link, so announce with it and fetch the info dict from the peers found
+async fn magnet_torrent(magnet: Magnet) -> Torrent {
+
+    println!("Info hash:{}", hex::encode(magnet.info_hash));
+    if let Some(name) = &magnet.name {
+        println!("Name:{}", name);
+    }
+
+    // Size is unknown until the metadata is fetched, announce a placeholder so we are not taken for a seeder
+    let peer_id = helpers::gen_random_id();
+    let mut tiers = AnnounceTiers::new(None, Some(vec![magnet.trackers.clone()]));
+    let mut peers = magnet.ipv4_peers();
+    if let Some(found) = tracker::announce(magnet.info_hash, tracker::UNKNOWN_LENGTH, peer_id, &mut tiers, Arc::new(Mutex::new(0))).await {
+        peers.extend(found.into_iter().filter(|peer| !peers.contains(peer)).collect::<Vec<_>>());
+    }
+
+    println!("Fetching metadata from {} peers", peers.len());
+    let info = download::fetch_metadata(&peers, magnet.info_hash, peer_id)
+        .await
+        .unwrap_or_else(|| exit_with("Could not fetch torrent metadata from peers"));
+
+    let torrent = Torrent::new(Metainfo::from_info(info, vec![magnet.trackers]).unwrap_or_else(|e| exit_with(e)));
+
+    // Peers that answered already have the torrent, start with them
+    torrent.peer_list.lock().await.extend(peers);
+    torrent
+
+}
+
+// Inspect bencoded files as JSON, optionally only the value at a path, or convert JSON back to bencode
+fn bencode_command(args: Vec<String>) {
+
+    let usage = "usage: cargo run bencode dump source_file [path] [--base64]\n       cargo run bencode from-json source_json destination_file";
+
+    match args.first().map(String::as_str) {
+        Some("dump") if args.len() >= 2 => {
+
+            let format = if args[2..].iter().any(|arg| arg == "--base64") { BytesFormat::Base64 } else { BytesFormat::Hex };
+            let query = args[2..].iter().find(|arg| !arg.starts_with("--"));
+
+            // Broken files are what this is for, so accept non-canonical input and report it
+            let buf = fs::read(&args[1]).unwrap_or_else(|e| exit_with(e));
+            let (value, warnings) = Bencode::decode_value_with_warnings(&buf, &DecodeOptions::default().lenient()).unwrap_or_else(|e| exit_with(e));
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+
+            let value = match query {
+                Some(query) => value.lookup(query).unwrap_or_else(|e| exit_with(e)),
+                None => &value
+            };
+            println!("{}", serde_json::to_string_pretty(&json::to_json(value, format)).unwrap());
+
+        },
+        Some("from-json") if args.len() == 3 => {
+
+            let source = fs::read(&args[1]).unwrap_or_else(|e| exit_with(e));
+            let parsed: serde_json::Value = serde_json::from_slice(&source).unwrap_or_else(|e| exit_with(e));
+            let element = json::from_json(&parsed).unwrap_or_else(|e| exit_with(e));
+            fs::write(&args[2], Bencode::encode(&element)).unwrap_or_else(|e| exit_with(e));
+
+        },
+        _ => panic!("{}", usage)
+    }
+
+}
+
+// Make a .torrent for a file or directory
+fn create_command(args: Vec<String>) {
+
+    let usage = "usage: cargo run create source_path destination_torrent [--tracker url[,url...]]... [--web-seed url]... \
+                 [--comment text] [--created-by text] [--source tag] [--piece-length bytes] [--private] [--no-date]";
+
+    if args.len() < 2 {
+        panic!("{}", usage);
+    }
+
+    let mut opts = CreateOptions {
+        created_by: Some(format!("rTorrent {}", env!("CARGO_PKG_VERSION"))),
+        creation_date: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs() as i64),
+        ..CreateOptions::default()
+    };
+
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--private" => opts.private = true,
+            "--no-date" => opts.creation_date = None,
+            _ => {
+                let value = flags.next().unwrap_or_else(|| panic!("{}", usage)).to_string();
+                match flag.as_str() {
+                    // Each --tracker is one tier, urls within a tier are separated by commas
+                    "--tracker" => opts.announce_list.push(value.split(',').map(str::to_string).collect()),
+                    "--web-seed" => opts.web_seeds.push(value),
+                    "--comment" => opts.comment = Some(value),
+                    "--created-by" => opts.created_by = Some(value),
+                    "--source" => opts.source = Some(value),
+                    "--piece-length" => opts.piece_length = Some(value.parse().unwrap_or_else(|e| exit_with(e))),
+                    _ => panic!("{}", usage)
+                }
+            }
+        }
+    }
+
+    let buf = create::create_torrent(Path::new(&args[0]), &opts).unwrap_or_else(|e| exit_with(e));
+    fs::write(&args[1], buf).unwrap_or_else(|e| exit_with(e));
+
+}
+
+// Print what a .torrent file describes
+fn info_command(args: Vec<String>) {
+
+    if args.len() != 1 {
+        panic!("usage: cargo run info source_torrent");
+    }
+
+    let buf = fs::read(&args[0]).unwrap_or_else(|e| exit_with(e));
+    let metainfo = Metainfo::from_bytes(&buf).unwrap_or_else(|e| exit_with(e));
+    for warning in &metainfo.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    println!("Name:{}", metainfo.name);
+    println!("Info hash:{}", hex::encode(metainfo.info_hash));
+    if let Some(v2) = &metainfo.v2 {
+        println!("Info hash v2:{}", hex::encode(v2.info_hash));
+    }
+    println!("Magnet:{}", metainfo.magnet());
+    println!("Piece size:{}", helpers::format_size(metainfo.piece_length));
+    println!("Pieces:{}", metainfo.piece_count());
+    println!("Total size:{} ({} bytes)", helpers::format_size(metainfo.length), metainfo.length);
+    println!("Private:{}", if metainfo.private { "yes" } else { "no" });
+
+    let optional = [
+        ("Comment", metainfo.comment.clone()),
+        ("Created by", metainfo.created_by.clone()),
+        ("Creation date", metainfo.creation_date.map(|date| date.to_string())),
+        ("Encoding", metainfo.encoding.clone()),
+        ("Source", metainfo.source.clone()),
+        ("Update url", metainfo.update_url.clone()),
+        ("Originator", metainfo.originator.as_deref().map(torrent_parser::escape_bytes))
+    ];
+    for (label, value) in optional {
+        if let Some(value) = value {
+            println!("{}:{}", label, value);
+        }
+    }
+
+    let tiers = match &metainfo.announce_list {
+        Some(tiers) => tiers.clone(),
+        None => metainfo.announce.iter().map(|url| vec![url.clone()]).collect()
+    };
+    println!("Trackers:");
+    for (i, tier) in tiers.iter().enumerate() {
+        println!("  Tier {}:{}", i + 1, tier.join(" "));
+    }
+
+    let seeds = [("Web seeds", metainfo.url_list.clone()), ("HTTP seeds", metainfo.http_seeds.clone())];
+    for (label, urls) in seeds.iter().filter(|(_, urls)| !urls.is_empty()) {
+        println!("{}:", label);
+        for url in urls {
+            println!("  {}", url);
+        }
+    }
+    if !metainfo.similar.is_empty() {
+        println!("Similar torrents:");
+        for hash in &metainfo.similar {
+            println!("  {}", hex::encode(hash));
+        }
+    }
+    if !metainfo.collections.is_empty() {
+        println!("Collections:{}", metainfo.collections.join(", "));
+    }
+    if !metainfo.nodes.is_empty() {
+        println!("Nodes:");
+        for (host, port) in &metainfo.nodes {
+            println!("  {}:{}", host, port);
+        }
+    }
+
+    println!("Files:");
+    match &metainfo.files {
+        Some(files) => {
+            for file in files.iter().filter(|file| !file.attr.padding) {
+                println!("  {} ({})", file.path.join("/"), helpers::format_size(file.length));
+            }
+        },
+        None => println!("  {} ({})", metainfo.name, helpers::format_size(metainfo.length))
+    }
+
+}
+
+fn exit_with(err: impl fmt::Display) -> ! {
+    eprintln!("{}", err);
+    process::exit(1)
+}
+
+// Create a file of a multi-file torrent below root following its attributes (BEP 47)
+// Path is the sanitized path of the file relative to root
+fn create_file(root: &Path, path: &Path, file: &TorrentFile) -> Option<File> {
+
+    if file.attr.padding {
+        return None;
+    }
+
+    let depth = path.components().count() - 1;
+    let path = root.join(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+
+    if let Some(target) = file.symlink_path.as_ref().filter(|_| file.attr.symlink) {
+        // Target is relative to the torrent root, so climb out of the directories of the link first
+        match sanitize::sanitize_path(target) {
+            Ok(target) => {
+                let target = Path::new(&"../".repeat(depth)).join(target.path);
+                if fs::symlink_metadata(&path).is_err() {
+                    symlink(target, &path).unwrap();
+                }
+            },
+            Err(e) => println!("Warning: symlink {} skipped, {}", path.display(), e)
+        }
+        return None;
+    }
+
+    let opened = open_file(path);
+    if file.attr.executable {
+        let mut permissions = opened.metadata().unwrap().permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        opened.set_permissions(permissions).unwrap();
+    }
+    Some(opened)
 
 }
 
@@ -95,42 +478,38 @@ fn open_file(path: PathBuf) -> File {
         .read(true)
         .write(true)
         .create(true)
+        // Data already downloaded is checked and kept
+        .truncate(false)
         .open(path)
         .unwrap() 
 }
 
-async fn verify_file(freq_ref: Arc<Mutex<Vec<Piece>>>, file_ref: Arc<Vec<(File,u64)>>, piece_hashes: Arc<Vec<Vec<u8>>>, downloaded: Arc<Mutex<u64>>, piece_left: Arc<Mutex<u16>>)  {
+// Pieces in resumed are trusted without hashing them, delete the resume file to check everything again
+async fn verify_file(freq_ref: Arc<Mutex<Vec<Piece>>>, file_ref: Arc<TorrentFiles>, metainfo: Arc<Metainfo>, downloaded: Arc<Mutex<u64>>, have: Arc<Mutex<Bitfield>>, resumed: Option<Bitfield>)  {
 
     println!("Checking already downloaded");
 
     let start = time::Instant::now();
-    let len = (*freq_ref.lock().await).len();
 
     // let mut total: u64 = 0;
     let mut handles = Vec::new();
+    let resumed = Arc::new(resumed.unwrap_or_else(|| Bitfield::new(metainfo.piece_count())));
     
-    for ind in 0..len {
+    for ind in 0..metainfo.piece_count() {
 
         let freq = freq_ref.clone();
-        let (downloaded, piece_left) = (downloaded.clone(), piece_left.clone());
-        let (length, offset, file, hash);
-        {
-            let ref1 = freq.lock().await;
-            (length, offset, file, hash) = ((*ref1)[ind].length, (*ref1)[ind].blocks[0].offset, file_ref.clone(), (*piece_hashes)[ind].clone())
-        }
+        let (downloaded, have, metainfo, resumed) = (downloaded.clone(), have.clone(), metainfo.clone(), resumed.clone());
+        let file = file_ref.clone();
 
         let h = tokio::spawn(async move {
 
-            if download::verify_piece(length, offset, file, &hash) {
-
-                let mut ref1 = freq.lock().await;
-                (*ref1)[ind].completed = true;
+            if resumed.get(ind) || download::verify_piece(&file, ind, &metainfo) {
 
+                let ref1 = freq.lock().await;
                 let mut download = downloaded.lock().await;
-                *download += (*ref1)[ind].length;
+                *download += (*ref1)[ind as usize].length;
 
-                let mut left = piece_left.lock().await;
-                *left -= 1;
+                have.lock().await.set(ind, true);
             }
         });
         handles.push(h);
@@ -144,4 +523,21 @@ async fn verify_file(freq_ref: Arc<Mutex<Vec<Piece>>>, file_ref: Arc<Vec<(File,u
 
     println!("Elapsed:{:.2?}\n",elapsed);
 
-}
\ No newline at end of file
+}
+
+// Save the pieces we have every 30 seconds until the download is complete
+// Stamps are taken after the pieces so data written in between only makes the next start hash again
+async fn save_resume(path: PathBuf, info_hash: [u8; 20], have: Arc<Mutex<Bitfield>>, files: Arc<TorrentFiles>) {
+
+    loop {
+        let have = have.lock().await.clone();
+        if let Err(e) = resume::stamps(&files).and_then(|stamps| resume::save(&path, info_hash, &have, &stamps)) {
+            eprintln!("Warning: could not save resume data, {}", e);
+        }
+        if have.is_complete() {
+            break;
+        }
+        time::sleep(time::Duration::from_secs(30)).await;
+    }
+
+}
@@ -0,0 +1,143 @@
+use std::io::{self, Read};
+use sha2::{Digest, Sha256};
+
+// Leaves of v2 merkle trees are SHA-256 hashes of 16 KiB blocks (BEP 52)
+pub const MERKLE_BLOCK_SIZE: usize = 16384;
+
+pub type Hash = [u8; 32];
+
+// Hash used for leaves past the end of a file
+const ZERO: Hash = [0; 32];
+
+pub fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+///Root of a merkle tree
+///`width` is the number of nodes in the bottom layer, a power of two, nodes past the end of `hashes` are `pad`
+pub fn merkle_root(hashes: &[Hash], width: usize, pad: Hash) -> Hash {
+
+    let mut layer = hashes.to_vec();
+    let (mut width, mut pad) = (width.max(1), pad);
+
+    while width > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+
+    layer.first().copied().unwrap_or(pad)
+
+}
+
+/// Leaf hashes of data, the last block may be shorter
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(sha256).collect()
+}
+
+/// Piece layer node for a piece whose blocks hash to `leaves`, short last pieces are padded with zero leaves
+pub fn piece_hash(leaves: &[Hash], piece_length: u64) -> Hash {
+    merkle_root(leaves, piece_length as usize / MERKLE_BLOCK_SIZE, ZERO)
+}
+
+/// Piece layer node of a piece that is entirely past the end of a file
+pub fn pad_hash(piece_length: u64) -> Hash {
+    merkle_root(&[], piece_length as usize / MERKLE_BLOCK_SIZE, ZERO)
+}
+
+/// Pieces root of a file from its leaf hashes, None for empty files
+pub fn file_root(leaves: &[Hash], piece_length: u64) -> Option<Hash> {
+
+    if leaves.is_empty() {
+        return None;
+    }
+
+    let per_piece = piece_length as usize / MERKLE_BLOCK_SIZE;
+    if leaves.len() <= per_piece {
+        return Some(merkle_root(leaves, leaves.len().next_power_of_two(), ZERO));
+    }
+
+    let layer: Vec<Hash> = leaves.chunks(per_piece).map(|piece| piece_hash(piece, piece_length)).collect();
+    Some(layer_root(&layer, piece_length))
+
+}
+
+/// Pieces root from the piece layer of a file larger than one piece
+pub fn layer_root(layer: &[Hash], piece_length: u64) -> Hash {
+    merkle_root(layer, layer.len().next_power_of_two(), pad_hash(piece_length))
+}
+
+/// Leaf hashes of everything read from reader
+pub fn read_block_hashes<R: Read>(reader: &mut R) -> io::Result<Vec<Hash>> {
+
+    let mut leaves = Vec::new();
+    let mut buf = vec![0u8; MERKLE_BLOCK_SIZE];
+
+    loop {
+        // Fill a whole block unless the reader ends
+        let mut filled = 0;
+        while filled < buf.len() {
+            match reader.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        leaves.push(sha256(&buf[..filled]));
+        if filled < buf.len() {
+            break;
+        }
+    }
+
+    Ok(leaves)
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_hashes, file_root, layer_root, merkle_root, pad_hash, piece_hash, read_block_hashes, sha256, MERKLE_BLOCK_SIZE};
+
+    #[test]
+    fn merkle_roots() {
+        let (a, b, c) = (sha256(b"a"), sha256(b"b"), sha256(b"c"));
+        let ab = sha256(&[a, b].concat());
+        let c0 = sha256(&[c, [0; 32]].concat());
+
+        assert_eq!(merkle_root(&[a], 1, [0; 32]), a);
+        assert_eq!(merkle_root(&[a, b], 2, [0; 32]), ab);
+        assert_eq!(merkle_root(&[a, b, c], 4, [0; 32]), sha256(&[ab, c0].concat()));
+    }
+
+    #[test]
+    fn file_roots() {
+        let piece_length = 2 * MERKLE_BLOCK_SIZE as u64;
+        let data: Vec<u8> = (0..MERKLE_BLOCK_SIZE * 5 + 7).map(|i| (i % 251) as u8).collect();
+        let leaves = block_hashes(&data);
+        assert_eq!(leaves.len(), 6);
+
+        // Same root whether computed from all leaves or through the piece layer
+        let layer: Vec<_> = leaves.chunks(2).map(|piece| piece_hash(piece, piece_length)).collect();
+        let root = file_root(&leaves, piece_length).unwrap();
+        assert_eq!(root, layer_root(&layer, piece_length));
+        assert_eq!(root, merkle_root(&leaves, 8, [0; 32]));
+        assert_eq!(pad_hash(piece_length), sha256(&[[0; 32], [0; 32]].concat()));
+
+        // Single piece files use the tree of their own blocks
+        assert_eq!(file_root(&leaves[..1], piece_length), Some(leaves[0]));
+        assert_eq!(file_root(&[], piece_length), None);
+
+        assert_eq!(read_block_hashes(&mut data.as_slice()).unwrap(), leaves);
+    }
+}
@@ -1,4 +1,5 @@
 use byteorder::{WriteBytesExt, BigEndian, ReadBytesExt};
+use crate::bitfield::Bitfield;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -96,8 +97,12 @@ impl Message {
         buf
     }
 
-    fn build_bitfield(bitfield: Vec<u8>) -> Message {
-        Message::BitField { length: 1+(bitfield.len() as u32), id: 5, bitfield}
+    pub fn build_bitfield(bitfield: &Bitfield) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(1 + bitfield.as_bytes().len() as u32).unwrap();
+        buf.write_u8(5).unwrap();
+        buf.extend_from_slice(bitfield.as_bytes());
+        buf
     }
 
     pub fn build_request(index: u32, begin: u32, req_length: u32) -> Vec<u8> {
@@ -131,8 +136,21 @@ impl Message {
     fn build_port(listen_port: u16) -> Message {
         Message::Port { length: 3, id: 9, listen_port }
     } 
+
+    // Extension protocol message, payload is the bencoded dict and any data after it
+    pub fn build_extended(extended_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(2 + payload.len() as u32).unwrap();
+        buf.write_u8(20).unwrap();
+        buf.write_u8(extended_id).unwrap();
+        buf.extend_from_slice(payload);
+        buf
+    }
 }
 
+// Reserved bit announcing support for the extension protocol (BEP 10)
+pub const EXTENSION_BIT: u64 = 1 << 20;
+
 pub struct HandshakeMsg {
     pstrlen: u8,
     pstr: String,
@@ -148,7 +166,7 @@ impl HandshakeMsg {
         let handshake = HandshakeMsg {
             pstrlen: 19,
             pstr: "BitTorrent protocol".to_string(),
-            reserved: 0,
+            reserved: EXTENSION_BIT,
             info_hash,
             peer_id
         };
@@ -172,13 +190,18 @@ impl HandshakeMsg {
 
         buf
     }
+
+    // Whether the reserved bytes of a received handshake announce extension protocol support
+    pub fn supports_extensions(reserved: &[u8]) -> bool {
+        reserved.len() == 8 && u64::from_be_bytes(reserved.try_into().unwrap()) & EXTENSION_BIT != 0
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::helpers::gen_random_id;
+    use crate::{bitfield::Bitfield, helpers::gen_random_id};
 
-    use super::HandshakeMsg;
+    use super::{HandshakeMsg, Message};
 
     #[test]
     fn test_build_msg() {
@@ -186,6 +209,15 @@ mod tests {
 
         let buf = HandshakeMsg::build_msg(gen_random_id(), gen_random_id());
         assert_eq!(buf.len(), 68);
+        assert_eq!(buf[25], 0x10);
+        assert!(HandshakeMsg::supports_extensions(&buf[20..28]));
+
+    }
 
+    #[test]
+    fn test_build_bitfield() {
+        let mut bitfield = Bitfield::new(9);
+        bitfield.set(8, true);
+        assert_eq!(Message::build_bitfield(&bitfield), vec![0, 0, 0, 3, 5, 0, 0x80]);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use sha1_smol::Sha1;
+use crate::bencoded_parser::{self, Bencode, DecodeOptions, Progress};
+
+// Message id of extension protocol messages (BEP 10)
+pub const EXTENDED_ID: u8 = 20;
+// Extended message id of the extension handshake
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// Extended message id we ask peers to use for ut_metadata messages sent to us
+pub const UT_METADATA_ID: u8 = 1;
+
+// Metadata is exchanged in pieces of 16 KiB, only the last one may be shorter (BEP 9)
+pub const METADATA_PIECE_SIZE: usize = 16384;
+// Largest info dict accepted from a peer
+pub const MAX_METADATA_SIZE: usize = 8 << 20;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+/// Extension handshake, other keys sent by peers are ignored
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ExtendedHandshake {
+    // Extension name to the id the sender wants for it, 0 disables it
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    pub metadata_size: Option<i64>
+}
+
+impl ExtendedHandshake {
+
+    /// Our handshake, metadata_size is only sent when we have the metadata
+    pub fn new(metadata_size: Option<usize>) -> ExtendedHandshake {
+        ExtendedHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID as i64)]),
+            metadata_size: metadata_size.map(|size| size as i64)
+        }
+    }
+
+    pub fn parse(payload: &[u8]) -> Option<ExtendedHandshake> {
+        bencoded_parser::from_bytes_with(payload, &DecodeOptions::untrusted().lenient()).ok()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Only strings and integers, can not fail
+        bencoded_parser::to_bytes(self).unwrap()
+    }
+
+    /// Id the peer wants its ut_metadata messages sent with, None if it does not support it
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.m.get("ut_metadata").and_then(|&id| u8::try_from(id).ok()).filter(|&id| id != 0)
+    }
+
+}
+
+/// Header of a ut_metadata message, data messages carry the piece after it
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MetadataMessage {
+    pub msg_type: i64,
+    pub piece: i64,
+    pub total_size: Option<i64>
+}
+
+impl MetadataMessage {
+
+    pub fn request(piece: u32) -> Vec<u8> {
+        MetadataMessage { msg_type: MSG_REQUEST, piece: piece as i64, total_size: None }.to_bytes()
+    }
+
+    pub fn reject(piece: i64) -> Vec<u8> {
+        MetadataMessage { msg_type: MSG_REJECT, piece, total_size: None }.to_bytes()
+    }
+
+    /// Data message for a piece of metadata, rejected if the piece does not exist
+    pub fn data(piece: i64, metadata: &[u8]) -> Vec<u8> {
+
+        let start = usize::try_from(piece).ok().and_then(|piece| piece.checked_mul(METADATA_PIECE_SIZE));
+        match start {
+            Some(start) if start < metadata.len() => {
+                let end = metadata.len().min(start + METADATA_PIECE_SIZE);
+                let mut buf = MetadataMessage { msg_type: MSG_DATA, piece, total_size: Some(metadata.len() as i64) }.to_bytes();
+                buf.extend_from_slice(&metadata[start..end]);
+                buf
+            },
+            _ => MetadataMessage::reject(piece)
+        }
+
+    }
+
+    /// Answer to a request message, data if we have the metadata and reject otherwise
+    pub fn answer(&self, metadata: Option<&[u8]>) -> Option<Vec<u8>> {
+        if self.msg_type != MSG_REQUEST {
+            return None;
+        }
+        Some(match metadata {
+            Some(metadata) if !metadata.is_empty() => MetadataMessage::data(self.piece, metadata),
+            _ => MetadataMessage::reject(self.piece)
+        })
+    }
+
+    ///Parse ut_metadata message
+    ///Returns the header and the bytes following it, which are the piece for data messages
+    pub fn parse(payload: &[u8]) -> Option<(MetadataMessage, &[u8])> {
+        match Bencode::decode_prefix_with(payload, &DecodeOptions::untrusted().lenient()).ok()? {
+            Progress::Done { value, end } => Some((bencoded_parser::from_value(value).ok()?, &payload[end..])),
+            Progress::NeedMore => None
+        }
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.msg_type == MSG_DATA
+    }
+
+    pub fn is_reject(&self) -> bool {
+        self.msg_type == MSG_REJECT
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        bencoded_parser::to_bytes(self).unwrap()
+    }
+
+}
+
+/// Info dict being assembled from pieces received from a peer
+pub struct MetadataDownload {
+    info_hash: [u8; 20],
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>
+}
+
+impl MetadataDownload {
+
+    /// None if the advertised size is not usable
+    pub fn new(info_hash: [u8; 20], size: i64) -> Option<MetadataDownload> {
+        let size = usize::try_from(size).ok().filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)?;
+        let count = size.div_ceil(METADATA_PIECE_SIZE);
+        Some(MetadataDownload { info_hash, size, pieces: vec![None; count] })
+    }
+
+    /// Pieces not received yet
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.pieces.len() as u32).filter(|&i| self.pieces[i as usize].is_none()).collect()
+    }
+
+    /// Store a received piece, false if its index or length is wrong
+    pub fn receive(&mut self, piece: i64, data: &[u8]) -> bool {
+        let Some(index) = usize::try_from(piece).ok().filter(|&index| index < self.pieces.len()) else {
+            return false;
+        };
+        let expected = METADATA_PIECE_SIZE.min(self.size - index * METADATA_PIECE_SIZE);
+        if data.len() != expected {
+            return false;
+        }
+        self.pieces[index] = Some(data.to_vec());
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// Assembled info dict, None if pieces are missing or it does not match the info hash
+    pub fn finish(self) -> Option<Vec<u8>> {
+        let mut metadata = Vec::with_capacity(self.size);
+        for piece in self.pieces {
+            metadata.extend(piece?);
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&metadata);
+        (hasher.digest().bytes() == self.info_hash).then_some(metadata)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1_smol::Sha1;
+    use super::{ExtendedHandshake, MetadataDownload, MetadataMessage, METADATA_PIECE_SIZE};
+
+    #[test]
+    fn extended_handshake() {
+        let ours = ExtendedHandshake::new(Some(100));
+        assert_eq!(ours.to_bytes(), b"d1:md11:ut_metadatai1ee13:metadata_sizei100ee");
+
+        let theirs = ExtendedHandshake::parse(b"d1:md6:ut_pexi2e11:ut_metadatai3ee1:v4:test13:metadata_sizei31235ee").unwrap();
+        assert_eq!(theirs.ut_metadata(), Some(3));
+        assert_eq!(theirs.metadata_size, Some(31235));
+
+        assert_eq!(ExtendedHandshake::parse(b"d1:md11:ut_metadatai0eee").unwrap().ut_metadata(), None);
+        assert_eq!(ExtendedHandshake::parse(b"de").unwrap().ut_metadata(), None);
+    }
+
+    #[test]
+    fn metadata_messages() {
+        let metadata = vec![7u8; METADATA_PIECE_SIZE + 10];
+
+        let buf = MetadataMessage::request(1);
+        let (request, rest) = MetadataMessage::parse(&buf).unwrap();
+        assert!(rest.is_empty());
+
+        let answer = request.answer(Some(&metadata)).unwrap();
+        let (header, data) = MetadataMessage::parse(&answer).unwrap();
+        assert!(header.is_data());
+        assert_eq!(header.total_size, Some(metadata.len() as i64));
+        assert_eq!(data, &metadata[METADATA_PIECE_SIZE..]);
+
+        // Pieces past the end and requests without metadata are rejected
+        assert!(MetadataMessage::parse(&MetadataMessage::data(2, &metadata)).unwrap().0.is_reject());
+        assert!(MetadataMessage::parse(&request.answer(None).unwrap()).unwrap().0.is_reject());
+        assert_eq!(header.answer(Some(&metadata)), None);
+    }
+
+    #[test]
+    fn assemble_metadata() {
+        let metadata: Vec<u8> = (0..METADATA_PIECE_SIZE * 2 + 5).map(|i| i as u8).collect();
+        let mut hasher = Sha1::new();
+        hasher.update(&metadata);
+        let info_hash = hasher.digest().bytes();
+
+        let mut download = MetadataDownload::new(info_hash, metadata.len() as i64).unwrap();
+        assert_eq!(download.missing(), vec![0, 1, 2]);
+
+        // Wrong lengths and indices are refused
+        assert!(!download.receive(2, &metadata[..METADATA_PIECE_SIZE]));
+        assert!(!download.receive(3, &metadata[..5]));
+
+        for (i, piece) in metadata.chunks(METADATA_PIECE_SIZE).enumerate().rev() {
+            assert!(download.receive(i as i64, piece));
+        }
+        assert!(download.is_complete());
+        assert_eq!(download.finish(), Some(metadata.clone()));
+
+        // Hash mismatch
+        let mut download = MetadataDownload::new([0; 20], 5).unwrap();
+        download.receive(0, &metadata[..5]);
+        assert_eq!(download.finish(), None);
+
+        assert!(MetadataDownload::new(info_hash, 0).is_none());
+        assert!(MetadataDownload::new(info_hash, i64::MAX).is_none());
+    }
+}
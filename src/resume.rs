@@ -0,0 +1,129 @@
+use std::{fs::{self, File}, io, path::{Path, PathBuf}, time::UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::{bencoded_parser::{self, DecodeOptions}, bitfield::Bitfield, download::TorrentFiles};
+
+/// Pieces of a download known to be complete, so a restart does not hash them again
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct ResumeData {
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    pieces: u32,
+    #[serde(with = "serde_bytes")]
+    have: Vec<u8>,
+    files: Vec<FileStamp>
+}
+
+/// Size and modification time of a file of the download when resume data was saved
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileStamp {
+    pub length: u64,
+    // Nanoseconds since the epoch
+    pub mtime: u64
+}
+
+impl FileStamp {
+
+    /// Stamp of a file as it is now
+    pub fn of(file: &File) -> io::Result<FileStamp> {
+        let metadata = file.metadata()?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        Ok(FileStamp { length: metadata.len(), mtime })
+    }
+
+}
+
+///Stamps of all files of a download, files not on disk such as padding get an empty stamp
+pub fn stamps(files: &TorrentFiles) -> io::Result<Vec<FileStamp>> {
+    files.files.iter().map(|file| file.as_ref().map_or(Ok(FileStamp::default()), FileStamp::of)).collect()
+}
+
+///Save the pieces we have for the torrent with info_hash, along with the stamps of its files
+///Written to a temporary file first so an interrupted save leaves the old data in place
+pub fn save(path: &Path, info_hash: [u8; 20], have: &Bitfield, files: &[FileStamp]) -> io::Result<()> {
+
+    let data = ResumeData { info_hash: info_hash.to_vec(), pieces: have.len(), have: have.as_bytes().to_vec(), files: files.to_vec() };
+    let buf = bencoded_parser::to_bytes(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, buf)?;
+    fs::rename(tmp, path)
+
+}
+
+///Pieces saved for the torrent with info_hash
+///None if there is no resume data, it belongs to another torrent or any file changed size or was modified since it was saved
+pub fn load(path: &Path, info_hash: [u8; 20], pieces: u32, files: &[FileStamp]) -> Option<Bitfield> {
+
+    // Limits sized from the torrent, one stamp per file and one bit per piece
+    let opts = DecodeOptions { max_string_len: (pieces as usize).div_ceil(8).max(20), max_items: files.len().max(4), ..DecodeOptions::default() };
+    let buf = fs::read(path).ok()?;
+    let data: ResumeData = bencoded_parser::from_bytes_with(&buf, &opts).ok()?;
+    if data.info_hash != info_hash || data.pieces != pieces || data.files != files {
+        return None;
+    }
+    Bitfield::from_bytes(&data.have, pieces).ok()
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, time::{Duration, SystemTime}};
+    use crate::bitfield::Bitfield;
+    use super::{load, save, FileStamp};
+
+    #[test]
+    fn resume_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.resume");
+        let stamps = [FileStamp { length: 5, mtime: 7 }, FileStamp::default()];
+
+        let mut have = Bitfield::new(12);
+        have.set(3, true);
+        have.set(11, true);
+        save(&path, [1; 20], &have, &stamps).unwrap();
+
+        assert_eq!(load(&path, [1; 20], 12, &stamps), Some(have));
+        assert_eq!(load(&path, [2; 20], 12, &stamps), None);
+        assert_eq!(load(&path, [1; 20], 13, &stamps), None);
+        assert_eq!(load(&dir.path().join("missing"), [1; 20], 12, &stamps), None);
+    }
+
+    #[test]
+    fn changed_files_are_not_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.resume");
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(dir.path().join("data")).unwrap();
+        file.set_len(100).unwrap();
+
+        let mut have = Bitfield::new(1);
+        have.set(0, true);
+        save(&path, [1; 20], &have, &[FileStamp::of(&file).unwrap()]).unwrap();
+        assert_eq!(load(&path, [1; 20], 1, &[FileStamp::of(&file).unwrap()]), Some(have));
+
+        // Touched after the save
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(load(&path, [1; 20], 1, &[FileStamp::of(&file).unwrap()]), None);
+
+        // Truncated, even with the old modification time
+        let modified = FileStamp::of(&file).unwrap().mtime;
+        file.set_len(50).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_nanos(modified)).unwrap();
+        assert_eq!(load(&path, [1; 20], 1, &[FileStamp::of(&file).unwrap()]), None);
+    }
+
+    #[test]
+    fn large_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.resume");
+
+        // More files and a larger bitfield than untrusted input may have
+        let stamps: Vec<_> = (0..20000).map(|i| FileStamp { length: i, mtime: i * 7 }).collect();
+        let mut have = Bitfield::new(9_000_000);
+        have.set(8_999_999, true);
+        save(&path, [1; 20], &have, &stamps).unwrap();
+
+        assert_eq!(load(&path, [1; 20], 9_000_000, &stamps), Some(have));
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    fmt, fs::{self, File}, io::{self, Seek, SeekFrom}, os::unix::fs::FileExt, path::PathBuf
+};
+use rayon::prelude::*;
+use crate::{create, download::{self, TorrentFiles}, torrent_parser::Metainfo};
+
+/// File of an earlier download that may hold data of the new torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub path: PathBuf,
+    // Components below the directory it was found in
+    pub relative: Vec<String>,
+    pub length: u64
+}
+
+/// What was taken from earlier downloads
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReuseStats {
+    pub files: usize,
+    pub pieces: u32,
+    pub skipped: Vec<Skipped>
+}
+
+/// File or directory left out because it could not be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: String
+}
+
+impl Skipped {
+
+    fn new(path: PathBuf, e: io::Error) -> Skipped {
+        Skipped { path, reason: e.to_string() }
+    }
+
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Skipped {} for reuse, {}", self.path.display(), self.reason)
+    }
+}
+
+///Files below the given directories, a path to a single file is taken as is
+///Names that are not UTF-8 are matched by size only, entries that can not be read are skipped and returned
+pub fn find_candidates(dirs: &[PathBuf]) -> (Vec<Candidate>, Vec<Skipped>) {
+
+    let mut candidates = Vec::new();
+    let mut skipped = Vec::new();
+    for dir in dirs {
+        let mut found = Vec::new();
+        let mut unreadable = Vec::new();
+        match fs::metadata(dir) {
+            Ok(metadata) if metadata.is_file() => found.push((dir.clone(), PathBuf::from(dir.file_name().unwrap_or_default()), metadata.len())),
+            Ok(_) => create::walk(dir, PathBuf::new(), &mut found, &mut unreadable),
+            Err(e) => unreadable.push((dir.clone(), e))
+        }
+
+        for (path, relative, length) in found {
+            let relative = relative.iter().map(|part| part.to_string_lossy().to_string()).collect();
+            candidates.push(Candidate { path, relative, length });
+        }
+        skipped.extend(unreadable.into_iter().map(|(path, e)| Skipped::new(path, e)));
+    }
+    (candidates, skipped)
+
+}
+
+///Copy data of files we already have into the storage of a new torrent (BEP 38)
+///Files are matched by size and then preferred by path, pieces lying inside a file are hash checked before anything is copied
+///A file whose pieces all match is copied whole if its new copy is still empty, letting the kernel reflink it where the filesystem can
+///Files too small to hold a whole piece are copied last and kept only if every piece they touch then matches
+///Candidates that can not be read are skipped and listed in the stats
+pub fn reuse_data(metainfo: &Metainfo, storage: &TorrentFiles, candidates: &[Candidate]) -> io::Result<ReuseStats> {
+
+    let mut stats = ReuseStats::default();
+    let mut small = Vec::new();
+    let paths: Vec<Vec<String>> = match &metainfo.files {
+        Some(files) => files.iter().map(|file| file.path.clone()).collect(),
+        None => vec![vec![metainfo.name.clone()]]
+    };
+
+    for (index, path) in paths.iter().enumerate() {
+
+        let length = storage.layout.file_length(index);
+        let Some(target) = &storage.files[index] else {
+            continue;
+        };
+        if length == 0 {
+            continue;
+        }
+
+        // Same size only, best path match first
+        let mut matches: Vec<&Candidate> = candidates.iter().filter(|candidate| candidate.length == length).collect();
+        matches.sort_by_key(|candidate| std::cmp::Reverse(path_score(&candidate.relative, path)));
+
+        let pieces = storage.layout.pieces_within(index);
+        if pieces.is_empty() {
+            small.push((index, target, matches));
+            continue;
+        }
+
+        for candidate in matches {
+            let Some(source) = open_candidate(candidate, &mut stats.skipped) else {
+                continue;
+            };
+            let matching = match matching_pieces(metainfo, storage, index, &source) {
+                Ok(matching) => matching,
+                Err(e) => {
+                    stats.skipped.push(Skipped::new(candidate.path.clone(), e));
+                    continue;
+                }
+            };
+
+            if matching.len() == pieces.len() && target.metadata()?.len() == 0 {
+                copy_file(&source, target)?;
+            }
+            else if !matching.is_empty() {
+                for &piece in &matching {
+                    let mut buf = vec![0u8; storage.layout.piece_size(piece).unwrap_or_default() as usize];
+                    source.read_exact_at(&mut buf, piece_offset(storage, index, piece))?;
+                    storage.write_at(piece, 0, &buf)?;
+                }
+            }
+            else {
+                continue;
+            }
+
+            stats.files += 1;
+            stats.pieces += matching.len() as u32;
+            break;
+        }
+
+    }
+
+    // Pieces of small files also hold data of the files around them, which is in place by now
+    for (index, target, matches) in small {
+
+        if target.metadata()?.len() != 0 {
+            continue;
+        }
+
+        for candidate in matches {
+            let Some(source) = open_candidate(candidate, &mut stats.skipped) else {
+                continue;
+            };
+            if let Err(e) = copy_file(&source, target) {
+                stats.skipped.push(Skipped::new(candidate.path.clone(), e));
+            }
+            else if storage.layout.pieces_overlapping(index).all(|piece| download::verify_piece(storage, piece, metainfo)) {
+                stats.files += 1;
+                break;
+            }
+            // Not the data we need, leave the file empty again
+            target.set_len(0)?;
+        }
+
+    }
+
+    Ok(stats)
+
+}
+
+fn open_candidate(candidate: &Candidate, skipped: &mut Vec<Skipped>) -> Option<File> {
+    File::open(&candidate.path).map_err(|e| skipped.push(Skipped::new(candidate.path.clone(), e))).ok()
+}
+
+// Number of trailing path components the two paths share
+fn path_score(candidate: &[String], path: &[String]) -> usize {
+    candidate.iter().rev().zip(path.iter().rev()).take_while(|(a, b)| a == b).count()
+}
+
+// Offset within its file of a piece lying inside file
+fn piece_offset(storage: &TorrentFiles, file: usize, piece: u32) -> u64 {
+    piece as u64 * storage.layout.piece_length() - storage.layout.file_start(file)
+}
+
+// Pieces lying inside file whose data in source matches their hash
+fn matching_pieces(metainfo: &Metainfo, storage: &TorrentFiles, file: usize, source: &File) -> io::Result<Vec<u32>> {
+
+    storage.layout
+        .pieces_within(file)
+        .into_par_iter()
+        .filter_map(|piece| {
+            let mut buf = vec![0u8; storage.layout.piece_size(piece).unwrap_or_default() as usize];
+            if let Err(e) = source.read_exact_at(&mut buf, piece_offset(storage, file, piece)) {
+                return Some(Err(e));
+            }
+            metainfo.verify_piece(piece, &buf).then_some(Ok(piece))
+        })
+        .collect()
+
+}
+
+// io::copy between files uses copy_file_range, which shares extents on filesystems that support it
+fn copy_file(source: &File, target: &File) -> io::Result<()> {
+    let (mut source, mut target) = (source, target);
+    source.seek(SeekFrom::Start(0))?;
+    target.seek(SeekFrom::Start(0))?;
+    io::copy(&mut source, &mut target)?;
+    Ok(())
+}
+
+/// Hint printed when the torrent says it shares data with others
+pub fn similar_hint(metainfo: &Metainfo) -> Option<String> {
+    if metainfo.similar.is_empty() && metainfo.collections.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "Torrent lists {} similar torrents and {} collections, pass --reuse with their download directories to copy matching data",
+        metainfo.similar.len(),
+        metainfo.collections.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use crate::{create::{create_torrent, CreateOptions}, download::TorrentFiles, layout::FileLayout, torrent_parser::Metainfo};
+    use std::os::unix::fs::symlink;
+    use super::{find_candidates, reuse_data, Candidate, ReuseStats};
+
+    #[test]
+    fn reuse_old_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new, storage) = (dir.path().join("old"), dir.path().join("new"), dir.path().join("storage"));
+        let same: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let mut changed = vec![7u8; 50000];
+
+        // Old version has the unchanged file under another name and an older copy of the changed one
+        fs::create_dir_all(&old).unwrap();
+        fs::write(old.join("renamed.bin"), &same).unwrap();
+        fs::write(old.join("b.bin"), &changed).unwrap();
+        fs::write(old.join("c.txt"), b"small").unwrap();
+
+        changed[20000] = 8;
+        fs::create_dir_all(&new).unwrap();
+        fs::write(new.join("a.bin"), &same).unwrap();
+        fs::write(new.join("b.bin"), &changed).unwrap();
+        fs::write(new.join("c.txt"), b"small").unwrap();
+
+        let opts = CreateOptions {
+            announce_list: vec![vec!["http://tracker/announce".to_string()]],
+            piece_length: Some(16384),
+            ..CreateOptions::default()
+        };
+        let metainfo = Metainfo::from_bytes(&create_torrent(&new, &opts).unwrap()).unwrap();
+
+        // Empty storage for the new version
+        fs::create_dir_all(&storage).unwrap();
+        let open = |name: &str| Some(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(storage.join(name)).unwrap());
+        let files = TorrentFiles { layout: FileLayout::from_metainfo(&metainfo), files: vec![open("a.bin"), open("b.bin"), open("c.txt")] };
+
+        let stats = reuse_data(&metainfo, &files, &find_candidates(&[old]).0).unwrap();
+
+        // a.bin is copied whole, b.bin only has pieces that did not change
+        // c.txt shares its piece with the part of b.bin that was not copied, so it can not be checked
+        assert_eq!(stats, ReuseStats { files: 2, pieces: 3, skipped: Vec::new() });
+        assert_eq!(fs::read(storage.join("a.bin")).unwrap(), same);
+        assert_eq!(fs::read(storage.join("c.txt")).unwrap(), b"");
+
+        // b.bin starts at 40000, piece 3 holds the changed byte and piece 4 is its bytes 25536..41920
+        let b = fs::read(storage.join("b.bin")).unwrap();
+        assert_eq!(b.len(), 41920);
+        assert!(b[..25536].iter().all(|&byte| byte == 0));
+        assert_eq!(b[25536..], changed[25536..41920]);
+    }
+
+    #[test]
+    fn small_files_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new, storage) = (dir.path().join("old"), dir.path().join("new"), dir.path().join("storage"));
+        let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+
+        // The old y.txt has the right size and path but other content, the right content has another name
+        fs::create_dir_all(&old).unwrap();
+        fs::write(old.join("x.bin"), &data).unwrap();
+        fs::write(old.join("y.txt"), b"smell").unwrap();
+        fs::write(old.join("other.txt"), b"small").unwrap();
+        // Symlink whose target is not there yet, as a download with symlink entries may have
+        symlink(old.join("later.bin"), old.join("link.bin")).unwrap();
+
+        fs::create_dir_all(&new).unwrap();
+        fs::write(new.join("x.bin"), &data).unwrap();
+        fs::write(new.join("y.txt"), b"small").unwrap();
+
+        let opts = CreateOptions {
+            announce_list: vec![vec!["http://tracker/announce".to_string()]],
+            piece_length: Some(16384),
+            ..CreateOptions::default()
+        };
+        let metainfo = Metainfo::from_bytes(&create_torrent(&new, &opts).unwrap()).unwrap();
+
+        fs::create_dir_all(&storage).unwrap();
+        let open = |name: &str| Some(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(storage.join(name)).unwrap());
+        let files = TorrentFiles { layout: FileLayout::from_metainfo(&metainfo), files: vec![open("x.bin"), open("y.txt")] };
+
+        // A candidate gone since it was found is skipped, as is the dangling symlink
+        let mut candidates = vec![Candidate { path: old.join("gone.bin"), relative: vec!["x.bin".to_string()], length: 20000 }];
+        let (found, skipped) = find_candidates(std::slice::from_ref(&old));
+        candidates.extend(found);
+        assert_eq!(skipped.iter().map(|skipped| skipped.path.clone()).collect::<Vec<_>>(), vec![old.join("link.bin")]);
+
+        let stats = reuse_data(&metainfo, &files, &candidates).unwrap();
+        assert_eq!((stats.files, stats.pieces), (2, 1));
+        assert_eq!(stats.skipped.iter().map(|skipped| skipped.path.clone()).collect::<Vec<_>>(), vec![old.join("gone.bin")]);
+        assert_eq!(fs::read(storage.join("x.bin")).unwrap(), data);
+        assert_eq!(fs::read(storage.join("y.txt")).unwrap(), b"small");
+    }
+}
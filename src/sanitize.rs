@@ -0,0 +1,298 @@
+use std::{collections::HashSet, fmt, path::PathBuf};
+
+// Longest file name most filesystems accept, in bytes
+const MAX_COMPONENT_LEN: usize = 255;
+// Extensions up to this length are kept when a long name is shortened
+const MAX_EXTENSION_LEN: usize = 16;
+
+// Names Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+/// Why a path component was changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeReason {
+    // Empty or `.`, dropped
+    Empty,
+    // `..`, dropped
+    Traversal,
+    // `/` or `\` inside a component, replaced
+    Separator,
+    // Control characters and characters not allowed on Windows, replaced
+    InvalidChar,
+    // Trailing dots and spaces, removed
+    TrailingDot,
+    // Device names such as CON or LPT1, prefixed
+    Reserved,
+    // Longer than 255 bytes, shortened
+    TooLong,
+    // Same path as an earlier file after sanitizing, suffixed
+    Duplicate,
+    // Same path as a directory holding other files, suffixed
+    DirectoryConflict
+}
+
+impl fmt::Display for ChangeReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            ChangeReason::Empty => "empty component",
+            ChangeReason::Traversal => "parent directory component",
+            ChangeReason::Separator => "embedded path separator",
+            ChangeReason::InvalidChar => "invalid character",
+            ChangeReason::TrailingDot => "trailing dot or space",
+            ChangeReason::Reserved => "reserved name",
+            ChangeReason::TooLong => "name too long",
+            ChangeReason::Duplicate => "duplicate path",
+            ChangeReason::DirectoryConflict => "path of a directory"
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Change made to one component of a path, `sanitized` is None when it was dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathChange {
+    pub original: String,
+    pub sanitized: Option<String>,
+    pub reasons: Vec<ChangeReason>
+}
+
+impl fmt::Display for PathChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reasons: Vec<String> = self.reasons.iter().map(ChangeReason::to_string).collect();
+        match &self.sanitized {
+            Some(sanitized) => write!(f, "Path component {:?} renamed to {:?}: {}", self.original, sanitized, reasons.join(", ")),
+            None => write!(f, "Path component {:?} removed: {}", self.original, reasons.join(", "))
+        }
+    }
+}
+
+/// Relative path that is safe to join onto a destination folder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SanitizedPath {
+    pub path: PathBuf,
+    pub changes: Vec<PathChange>
+}
+
+/// Path with no component left after sanitizing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsafePath {
+    pub path: String
+}
+
+impl fmt::Display for UnsafePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Path {:?} has no usable components", self.path)
+    }
+}
+
+impl std::error::Error for UnsafePath {}
+
+///Sanitize one path component
+///Returns None if it has to be dropped, along with the reasons it was changed
+pub fn sanitize_component(component: &str) -> (Option<String>, Vec<ChangeReason>) {
+
+    match component {
+        "" | "." => return (None, vec![ChangeReason::Empty]),
+        ".." => return (None, vec![ChangeReason::Traversal]),
+        _ => {}
+    }
+
+    let mut reasons = Vec::new();
+    let mut name = String::with_capacity(component.len());
+    for c in component.chars() {
+        if c == '/' || c == '\\' {
+            push_reason(&mut reasons, ChangeReason::Separator);
+            name.push('_');
+        }
+        else if c.is_control() || "<>:\"|?*".contains(c) {
+            push_reason(&mut reasons, ChangeReason::InvalidChar);
+            name.push('_');
+        }
+        else {
+            name.push(c);
+        }
+    }
+
+    let trimmed = name.trim_end_matches(['.', ' ']);
+    if trimmed.len() != name.len() {
+        reasons.push(ChangeReason::TrailingDot);
+        name = if trimmed.is_empty() { "_".to_string() } else { trimmed.to_string() };
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        reasons.push(ChangeReason::Reserved);
+        name.insert(0, '_');
+    }
+
+    if name.len() > MAX_COMPONENT_LEN {
+        reasons.push(ChangeReason::TooLong);
+        name = shorten(&name, MAX_COMPONENT_LEN);
+    }
+
+    (Some(name), reasons)
+
+}
+
+fn push_reason(reasons: &mut Vec<ChangeReason>, reason: ChangeReason) {
+    if !reasons.contains(&reason) {
+        reasons.push(reason);
+    }
+}
+
+// Cut a name to at most max bytes on a char boundary, keeping a short extension
+fn shorten(name: &str, max: usize) -> String {
+
+    let extension = name
+        .rfind('.')
+        .map(|dot| &name[dot..])
+        .filter(|extension| extension.len() <= MAX_EXTENSION_LEN && extension.len() < name.len());
+    let extension = extension.unwrap_or_default();
+
+    let mut end = max - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_string() + extension
+
+}
+
+///Sanitize a path given as components
+///Fails only if nothing is left, the result never leaves the folder it is joined onto
+pub fn sanitize_path(components: &[String]) -> Result<SanitizedPath, UnsafePath> {
+
+    let mut path = PathBuf::new();
+    let mut changes = Vec::new();
+
+    for component in components {
+        let (sanitized, reasons) = sanitize_component(component);
+        if !reasons.is_empty() {
+            changes.push(PathChange { original: component.clone(), sanitized: sanitized.clone(), reasons });
+        }
+        if let Some(sanitized) = sanitized {
+            path.push(sanitized);
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(UnsafePath { path: components.join("/") });
+    }
+
+    Ok(SanitizedPath { path, changes })
+
+}
+
+///Sanitize the paths of all files of a torrent
+///Paths that end up the same as an earlier one, or as a directory holding other files, get a numbered suffix
+pub fn sanitize_paths(paths: &[Vec<String>]) -> Result<Vec<SanitizedPath>, UnsafePath> {
+
+    let mut sanitized = paths.iter().map(|components| sanitize_path(components)).collect::<Result<Vec<_>, _>>()?;
+
+    // Directories are kept as they are, so a file is renamed when it has the path of one
+    let dirs: HashSet<PathBuf> = sanitized
+        .iter()
+        .flat_map(|entry| entry.path.ancestors().skip(1).map(PathBuf::from))
+        .collect();
+    let mut seen = HashSet::new();
+
+    for entry in &mut sanitized {
+        let reason = if dirs.contains(&entry.path) {
+            ChangeReason::DirectoryConflict
+        }
+        else if !seen.insert(entry.path.clone()) {
+            ChangeReason::Duplicate
+        }
+        else {
+            continue;
+        };
+
+        let file_name = entry.path.file_name().unwrap().to_string_lossy().to_string();
+        let mut i = 1;
+        let renamed = loop {
+            // Shortened first so the suffix is never cut off
+            let suffix = format!("_{}", i);
+            let name = if file_name.len() + suffix.len() > MAX_COMPONENT_LEN { shorten(&file_name, MAX_COMPONENT_LEN - suffix.len()) } else { file_name.clone() };
+            let candidate = entry.path.with_file_name(name + &suffix);
+            if !dirs.contains(&candidate) && seen.insert(candidate.clone()) {
+                break candidate;
+            }
+            i += 1;
+        };
+        entry.changes.push(PathChange {
+            original: file_name,
+            sanitized: Some(renamed.file_name().unwrap().to_string_lossy().to_string()),
+            reasons: vec![reason]
+        });
+        entry.path = renamed;
+    }
+
+    Ok(sanitized)
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{sanitize_component, sanitize_path, sanitize_paths, ChangeReason};
+
+    fn components(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|part| part.to_string()).collect()
+    }
+
+    #[test]
+    fn sanitize_components() {
+        assert_eq!(sanitize_component("file.txt"), (Some("file.txt".to_string()), vec![]));
+        assert_eq!(sanitize_component(".."), (None, vec![ChangeReason::Traversal]));
+        assert_eq!(sanitize_component(""), (None, vec![ChangeReason::Empty]));
+        assert_eq!(sanitize_component("/etc/passwd"), (Some("_etc_passwd".to_string()), vec![ChangeReason::Separator]));
+        assert_eq!(sanitize_component("a\u{0}b?c"), (Some("a_b_c".to_string()), vec![ChangeReason::InvalidChar]));
+        assert_eq!(sanitize_component("name. "), (Some("name".to_string()), vec![ChangeReason::TrailingDot]));
+        assert_eq!(sanitize_component("..."), (Some("_".to_string()), vec![ChangeReason::TrailingDot]));
+        assert_eq!(sanitize_component("con.txt"), (Some("_con.txt".to_string()), vec![ChangeReason::Reserved]));
+        assert_eq!(sanitize_component("console"), (Some("console".to_string()), vec![]));
+
+        let (long, reasons) = sanitize_component(&("é".repeat(200) + ".mkv"));
+        let long = long.unwrap();
+        assert_eq!(reasons, vec![ChangeReason::TooLong]);
+        assert!(long.len() <= 255 && long.ends_with("é.mkv"));
+    }
+
+    #[test]
+    fn sanitize_paths_stay_inside() {
+        let sanitized = sanitize_path(&components(&["..", "..", "etc", "passwd"])).unwrap();
+        assert_eq!(sanitized.path, PathBuf::from("etc/passwd"));
+        assert_eq!(sanitized.changes.len(), 2);
+        assert_eq!(sanitized.changes[0].to_string(), "Path component \"..\" removed: parent directory component");
+
+        assert!(sanitize_path(&components(&["..", "."])).is_err());
+        assert!(sanitize_path(&[]).is_err());
+
+        let all = sanitize_paths(&[components(&["a", "b?"]), components(&["a", "b*"]), components(&["a", "b_"])]).unwrap();
+        let paths: Vec<_> = all.iter().map(|entry| entry.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("a/b_"), PathBuf::from("a/b__1"), PathBuf::from("a/b__2")]);
+        assert_eq!(all[1].changes.last().unwrap().reasons, vec![ChangeReason::Duplicate]);
+    }
+
+    #[test]
+    fn file_and_directory_conflict() {
+        // A file named like the directory of later files, either way round
+        let all = sanitize_paths(&[components(&["a"]), components(&["a", "b"]), components(&["c", "d"]), components(&["c"])]).unwrap();
+        let paths: Vec<_> = all.iter().map(|entry| entry.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("a_1"), PathBuf::from("a/b"), PathBuf::from("c/d"), PathBuf::from("c_1")]);
+        assert_eq!(all[0].changes[0].reasons, vec![ChangeReason::DirectoryConflict]);
+        assert_eq!(all[3].changes[0].reasons, vec![ChangeReason::DirectoryConflict]);
+    }
+
+    #[test]
+    fn long_duplicates_stay_short() {
+        let long = "x".repeat(255);
+        let all = sanitize_paths(&[components(&[&long]), components(&[&long])]).unwrap();
+        let renamed = all[1].path.to_string_lossy().to_string();
+        assert_eq!(renamed.len(), 255);
+        assert!(renamed.ends_with("x_1"));
+    }
+}
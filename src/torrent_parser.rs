@@ -1,36 +1,128 @@
 use std::{
-    collections::{VecDeque, HashSet}, sync::Arc, {fmt,fs::File}
+    collections::{BTreeMap, HashMap, VecDeque, HashSet}, fmt, io::{self, Read, Write}, sync::Arc
 };
+use sha1_smol::Sha1;
 use tokio::sync::Mutex;
 use crate:: {
-    bencoded_parser::{Bencode, Element},
+    bitfield::Bitfield,
+    merkle,
+    magnet::Magnet,
+    bencoded_parser::{Bencode, DecodeError, DecodeOptions, DecodeWarning, Element, LookupError, LookupErrorKind},
     helpers::{self, BLOCK_SIZE}
 };
 
-pub struct Torrent {
-    pub announce_url: Option<String>,
-    pub announce_list: Option<Vec<String>>,
+mod text;
+mod v2;
+pub use text::{escape_bytes, TextDecoder};
+pub use v2::{V2File, V2Info, V2Piece};
+
+/// Contents of a .torrent file
+/// Never changes once parsed, share it behind an Arc
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metainfo {
+    pub announce: Option<String>,
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub name: String,
+    pub piece_length: u64,
+    // Total length of all files, padding included
     pub length: u64,
+    // Empty for torrents with only v2 hashes
+    pub piece_hashes: Vec<[u8; 20]>,
+    // None for single file torrents
+    pub files: Option<Vec<TorrentFile>>,
+    // Truncated v2 info hash for torrents with only v2 hashes
     pub info_hash: [u8; 20],
+    // Raw info dict as found in the torrent, its SHA-1 is the info hash of v1 and hybrid torrents
+    pub info_bytes: Vec<u8>,
+    // Present for torrents with `meta version` 2, hybrid torrents also have the v1 fields
+    pub v2: Option<V2Info>,
+    // Seconds since the unix epoch
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Encoding of the strings in the info dict, only set by old clients
+    pub encoding: Option<String>,
+    // Peers must only come from the trackers of the torrent (BEP 27)
+    pub private: bool,
+    // Tag some private trackers put in the info dict to change its hash
+    pub source: Option<String>,
+    // url-list web seeds (BEP 19), a single url is read as a list of one
+    pub url_list: Vec<String>,
+    // httpseeds (BEP 17)
+    pub http_seeds: Vec<String>,
+    // DHT bootstrap nodes as host and port (BEP 5)
+    pub nodes: Vec<(String, u16)>,
+    // Info hashes of torrents sharing files with this one (BEP 38)
+    pub similar: Vec<[u8; 20]>,
+    // Names of collections the torrent belongs to (BEP 38)
+    pub collections: Vec<String>,
+    // Feed publishing newer versions of the torrent (BEP 39)
+    pub update_url: Option<String>,
+    // Publisher of the torrent, a newer version must have the same one (BEP 39)
+    pub originator: Option<Vec<u8>>,
+    // Top-level keys not parsed into the fields above, kept to write the torrent back out
+    pub extra: BTreeMap<Vec<u8>, Element>,
+    // Non-canonical encodings found in the torrent file
+    pub warnings: Vec<DecodeWarning>
+}
+
+/// Download state of a torrent, shared between the tracker, peer and display tasks
+pub struct Torrent {
+    pub metainfo: Arc<Metainfo>,
     pub peer_list: Arc<Mutex<VecDeque<(u32,u16)>>>,
     pub peer_id: [u8; 20],
     pub piece_freq: Arc<Mutex<Vec<Piece>>>,
     pub downloaded: Arc<Mutex<u64>>,
     pub uploaded: Arc<Mutex<u64>>,
     pub connections: Arc<Mutex<HashSet<(u32,u16)>>>,
-    pub file_list: Option<Vec<(String, u64)>>,
-    pub piece_hashes: Arc<Vec<Vec<u8>>>,
-    pub piece_left: Arc<Mutex<u16>>
+    // Pieces we have verified
+    pub have: Arc<Mutex<Bitfield>>
+}
+
+/// File of a multi-file torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    // Components as given in the torrent, sanitize them before touching the disk
+    pub path: Vec<String>,
+    pub length: u64,
+    pub attr: FileAttr,
+    // Target of a symlink relative to the torrent root
+    pub symlink_path: Option<Vec<String>>
+}
+
+/// File attributes (BEP 47), unknown flags are ignored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttr {
+    // p, only there to align the next file and never stored
+    pub padding: bool,
+    // x
+    pub executable: bool,
+    // h
+    pub hidden: bool,
+    // l
+    pub symlink: bool
+}
+
+impl FileAttr {
+
+    pub fn parse(attr: &[u8]) -> FileAttr {
+        FileAttr {
+            padding: attr.contains(&b'p'),
+            executable: attr.contains(&b'x'),
+            hidden: attr.contains(&b'h'),
+            symlink: attr.contains(&b'l')
+        }
+    }
+
 }
 
 #[derive(Clone)]
 #[derive(Debug)]
 pub struct Piece {
-    pub ref_no: u16,
+    pub ref_no: u32,
     pub length: u64,
-    pub blocks: Vec<Block>,
-    pub completed: bool
+    // Empty until the piece is picked for download
+    pub blocks: Vec<Block>
 }
 
 #[derive(Clone)]
@@ -38,226 +130,690 @@ pub struct Piece {
 pub struct Block {
     pub is_req: bool,
     pub length: u64,
+    // Offset within the piece
     pub offset: u64
 }
 
-impl Torrent {
+impl Metainfo {
 
-    pub async fn parse_decoded(file: &mut File) -> Result<Torrent, InvalidTorrentFile> {
+    ///Parse a .torrent file
+    ///Real torrents are often not canonical, so the file is decoded leniently and problems are kept as warnings
+    pub fn from_bytes(buf: &[u8]) -> Result<Metainfo, InvalidTorrentFile> {
 
-        let (decoded, info_hash) = Bencode::decode(file).unwrap();
-        let (announce_url, announce_list, name, piece_length, hashes, length, piece_no, file_list) = Torrent::parse_decoded_helper(&decoded)?;
+        let (value, warnings) = Bencode::decode_value_with_warnings(buf, &DecodeOptions::default().lenient())?;
+        let info = value.get(b"info").ok_or(LookupError { path: "info".to_string(), kind: LookupErrorKind::Missing })?;
 
-        let mut no_blocks = piece_length/(BLOCK_SIZE as u64);
-        if piece_length/(BLOCK_SIZE as u64) != 0 { no_blocks += 1; }
+        Metainfo::from_decoded(&value.to_element(), info.raw.to_vec(), warnings)
 
-        let torrent = Torrent { 
-            announce_url, 
-            announce_list, 
-            name, 
-            length, 
-            info_hash, 
-            peer_list: Arc::new(Mutex::new(VecDeque::new())), 
-            peer_id: helpers::gen_random_id(), 
-            piece_freq: Arc::new(Mutex::new(Torrent::build_piece_freq(no_blocks, piece_no, piece_length, length))),
-            downloaded: Arc::new(Mutex::new(0)),
-            uploaded: Arc::new(Mutex::new(0)),
-            connections: Arc::new(Mutex::new(HashSet::new())),
-            file_list,
-            piece_hashes: Arc::new(hashes),
-            piece_left: Arc::new(Mutex::new(piece_no as u16))
-        };
+    }
+
+    ///Parse a .torrent file from any reader
+    pub fn from_reader<R: Read>(reader: &mut R) -> Result<Metainfo, InvalidTorrentFile> {
 
-        Ok(torrent)
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).map_err(|e| DecodeError::Io(e.kind()))?;
+        Metainfo::from_bytes(&buf)
 
     }
 
-    // Function to return Announce Url, name, piece length and hashes from a decoded torrent file
-    fn parse_decoded_helper(decoded: &Element) -> Result<(Option<String>, Option<Vec<String>>, String, u64, Vec<Vec<u8>>, u64, usize, Option<Vec<(String, u64)>>), InvalidTorrentFile> {
+    ///Build metainfo from an info dict fetched from peers
+    ///The info dict must already be checked against the info hash, trackers are the tiers to announce to
+    pub fn from_info(info: Vec<u8>, trackers: Vec<Vec<String>>) -> Result<Metainfo, InvalidTorrentFile> {
 
-        let mut announce = None;
-        let mut announce_list = None;
-        let mut name = String::new();
-        let mut piece_length = 0;
-        let mut hashes = Vec::new();
-        let mut length: u64 = 0;
-        let mut files: Option<Vec<(String, u64)>> = None;
-        let mut piece_no: usize = 0;
-
-        match decoded {
-            Element::Dict(mp) => {
-
-                // Get List of announce urls
-                if mp.contains_key("announce-list".as_bytes()) {
-                    let mut tmp = Vec::new();
-                    if let Element::List(l) = &mp["announce-list".as_bytes()] {
-                        for i in l {
-                            if let Element::List(l1) = i {
-                                if let Element::ByteString(s) = &l1[0] {
-                                    tmp.push(String::from_utf8(s.to_owned()).unwrap());
-                                }
-                            } 
-                        }
-                    }
-                    announce_list = Some(tmp);
-                }
-                else { 
-                    // Get Announce url of torrent file
-                    if mp.contains_key("announce".as_bytes()) {
-                        if let Element::ByteString(s) = &mp["announce".as_bytes()] { announce = Some(String::from_utf8(s.to_owned()).unwrap()); } 
-                        else { return Err(InvalidTorrentFile{case: 0}); }
-                    } 
-                    else { return Err(InvalidTorrentFile{case: 1}); }
-                }
+        let (value, warnings) = Bencode::decode_value_with_warnings(&info, &DecodeOptions::default().lenient())?;
+        let announce_list = trackers
+            .into_iter()
+            .map(|tier| Element::List(tier.into_iter().map(|url| Element::ByteString(url.into_bytes())).collect()))
+            .collect();
 
-                // Get info of torrent file
-                if mp.contains_key("info".as_bytes()) {
-
-                    match &mp["info".as_bytes()] {
-                        Element::Dict(info_mp) => {
-                            if !info_mp.contains_key("name".as_bytes()) || !info_mp.contains_key("piece length".as_bytes()) || !info_mp.contains_key("pieces".as_bytes()) || (!info_mp.contains_key("length".as_bytes()) && !info_mp.contains_key("files".as_bytes())) { return Err(InvalidTorrentFile{case: 6}); }
-
-                            // Name
-                            if let Element::ByteString(s) = &info_mp["name".as_bytes()] { name += &String::from_utf8_lossy(s); }
-                            
-                            // Piece Length
-                            if let Element::Integer(l) = &info_mp["piece length".as_bytes()] { piece_length += l; }
-                            
-                            // Piece Hashes
-                            if let Element::ByteString(s) = &info_mp["pieces".as_bytes()] {
-                                piece_no = s.len()/20;
-
-                                for i in (0..s.len()).step_by(20) {
-                                    let tmp = s[i..(i+20)].to_vec();
-                                    hashes.push(tmp);
-                                }
-
-                            }
-
-                            // Length for single file
-                            if info_mp.contains_key("length".as_bytes()) {
-                                if let Element::Integer(l) = &info_mp["length".as_bytes()] { length += l.abs() as u64; }
-                            }
-
-                            // Length for multiple files
-                            if info_mp.contains_key("files".as_bytes()) {
-
-                                let mut file_list = Vec::new();
-
-                                if let Element::List(files) = &info_mp["files".as_bytes()] {
-                                    for file in files {
-                                        if let Element::Dict(file_mp) = file {
-                                            
-                                            if let Element::Integer(l) = file_mp["length".as_bytes()] {
-
-                                                length += l.abs() as u64;
-                                                let mut path = String::new();
-
-                                                if let Element::List(v) = &file_mp["path".as_bytes()] {
-                                                    for s in v {
-                                                        if let Element::ByteString(part) = s {
-                                                            path += &(String::from_utf8(part.to_owned()).unwrap() + "/");
-                                                        }
-                                                    }
-                                                }
-                                                path.pop();
-                                                file_list.push((path, l.abs() as u64));
-                                            }
-
-
-                                        }
-                                    }
-                                }
-                                files = Some(file_list);
-                            }
-
-                        }
-                        _ => { return Err(InvalidTorrentFile{case: 3}); }
-                    }
-                }
-                else { return Err(InvalidTorrentFile { case: 4 }); }
+        let decoded = Element::Dict(BTreeMap::from([
+            (b"announce-list".to_vec(), Element::List(announce_list)),
+            (b"info".to_vec(), value.to_element())
+        ]));
 
-            },
-            _ => { return Err(InvalidTorrentFile{case: 5}); }
-            
+        Metainfo::from_decoded(&decoded, info, warnings)
+
+    }
+
+    ///Bencoded torrent file
+    ///The info dict is written exactly as it was parsed so the info hash does not change
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Writing into a Vec can not fail
+        self.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    ///Write the bencoded torrent file into writer
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+
+        let mut top = self.extra.clone();
+        if let Some(announce) = &self.announce {
+            top.insert(b"announce".to_vec(), Element::ByteString(announce.as_bytes().to_vec()));
+        }
+        if let Some(announce_list) = &self.announce_list {
+            let tiers = announce_list.iter().map(|tier| strings(tier)).collect();
+            top.insert(b"announce-list".to_vec(), Element::List(tiers));
+        }
+        if let Some(date) = self.creation_date {
+            top.insert(b"creation date".to_vec(), Element::Integer(date));
+        }
+        for (key, value) in [(b"comment".as_slice(), &self.comment), (b"created by", &self.created_by), (b"encoding", &self.encoding)] {
+            if let Some(value) = value {
+                top.insert(key.to_vec(), Element::ByteString(value.as_bytes().to_vec()));
+            }
+        }
+        if !self.url_list.is_empty() {
+            top.insert(b"url-list".to_vec(), strings(&self.url_list));
         }
-        Ok((announce,announce_list,name,piece_length as u64, hashes, length, piece_no, files))
+        if !self.http_seeds.is_empty() {
+            top.insert(b"httpseeds".to_vec(), strings(&self.http_seeds));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self.nodes
+                .iter()
+                .map(|(host, port)| Element::List(vec![Element::ByteString(host.as_bytes().to_vec()), Element::Integer(*port as i64)]))
+                .collect();
+            top.insert(b"nodes".to_vec(), Element::List(nodes));
+        }
+        // Placeholder to get the key in sorted position, the raw info dict is written instead
+        top.insert(b"info".to_vec(), Element::Integer(0));
+
+        w.write_all(b"d")?;
+        for (key, element) in &top {
+            Bencode::encode_to(&Element::ByteString(key.clone()), w)?;
+            if key == b"info" {
+                w.write_all(&self.info_bytes)?;
+            }
+            else {
+                Bencode::encode_to(element, w)?;
+            }
+        }
+        w.write_all(b"e")
+
     }
 
-    // Function to build the piece frequency array used by download
-    fn build_piece_freq(no_blocks: u64, piece_no: usize, piece_length: u64, length: u64) -> Vec<Piece> {
-        
-        // Vector of all pieces, for each piece contains all blocks for each block a bool and the size of the block
-        let mut piece_freq = vec! [
-            Piece {
-                ref_no: 0,
-                length: piece_length,
-                blocks: vec![
-                        Block {
-                            is_req: false,
-                            length: BLOCK_SIZE as u64,
-                            offset: 0
-                        }; 
-                        no_blocks as usize
-                    ],
-                completed: false
-            };
-            piece_no
-        ];
-        
-        // Check whether pieces can be perfectly divided into blocks or last block of piece should be lesser in size
-        if piece_length%(BLOCK_SIZE as u64) != 0 {
-            for piece in &mut piece_freq {
-                piece.blocks.last_mut().unwrap().length = piece_length%(BLOCK_SIZE as u64);
+    /// Info hashes the torrent is known by, v1 first and then the truncated v2 hash of hybrid torrents
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash];
+        hashes.extend(self.v2.as_ref().map(|v2| v2.truncated_hash()).filter(|hash| *hash != self.info_hash));
+        hashes
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        match &self.v2 {
+            Some(v2) if self.piece_hashes.is_empty() => v2.v1_pieces.len() as u32,
+            _ => self.piece_hashes.len() as u32
+        }
+    }
+
+    ///Check the data of a piece against the hashes of the torrent
+    ///Hybrid torrents must match both the v1 and the v2 hashes, torrents with only v2 hashes the v2 ones
+    pub fn verify_piece(&self, index: u32, data: &[u8]) -> bool {
+
+        if index >= self.piece_count() {
+            return false;
+        }
+        let v1 = self.piece_hashes.get(index as usize).is_none_or(|hash| {
+            let mut hasher = Sha1::new();
+            hasher.update(data);
+            hasher.digest().bytes() == *hash
+        });
+        v1 && self.v2.as_ref().is_none_or(|v2| v2.verify_v1_piece(index, data))
+
+    }
+
+    /// Magnet link for the torrent with its trackers and web seeds
+    pub fn magnet(&self) -> Magnet {
+        let trackers = match &self.announce_list {
+            Some(tiers) => tiers.concat(),
+            None => self.announce.iter().cloned().collect()
+        };
+        Magnet { info_hash: self.info_hash, name: Some(self.name.clone()), trackers, peers: Vec::new(), web_seeds: self.url_list.clone() }
+    }
+
+    // Build metainfo from a decoded torrent file and the raw bytes of its info dict
+    fn from_decoded(decoded: &Element, info_bytes: Vec<u8>, warnings: Vec<DecodeWarning>) -> Result<Metainfo, InvalidTorrentFile> {
+
+        let mut announce = None;
+        let mut announce_list = None;
+        let mut files: Option<Vec<TorrentFile>> = None;
+
+        // Strings that are not UTF-8 are transcoded from the encoding the torrent declares
+        let decoder = TextDecoder::new(decoded.get("encoding").and_then(Element::as_bytes));
+        let text = |element: &Element| element.as_bytes().map(|bytes| decoder.decode(bytes));
+
+        // Get tiers of announce urls, urls that are not strings are skipped
+        if decoded.get("announce-list").is_some() {
+            let mut tmp = Vec::new();
+            for tier in decoded.lookup_list("announce-list")? {
+                let urls = tier
+                    .as_list()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(text)
+                    .collect();
+                tmp.push(urls);
             }
+            announce_list = Some(tmp);
         }
-        
-        // Check whether last piece has same number of blocks as other pieces
-        let last_piece_length = length%piece_length;
-        if last_piece_length != 0 {
-            
-            piece_freq.last_mut().unwrap().length = last_piece_length;
 
-            let last_piece_block_no = last_piece_length/(BLOCK_SIZE as u64);
+        // Get Announce url of torrent file, only required without a list
+        if decoded.get("announce").is_some() || announce_list.is_none() {
+            announce = Some(decoder.decode(decoded.lookup_bytes("announce")?));
+        }
 
-            while piece_freq.last().unwrap().blocks.len() != last_piece_block_no as usize {
-                piece_freq.last_mut().unwrap().blocks.pop();
+        // Get info of torrent file
+        decoded.lookup_dict("info")?;
+        let name = decoder.lookup_string(decoded, "info", "name")?;
+        let piece_length = decoded.lookup_int("info.piece length")?;
+        if piece_length <= 0 {
+            return Err(InvalidTorrentFile::InvalidValue("info.piece length"));
+        }
+        let piece_length = piece_length as u64;
+
+        let mut v2 = V2Info::parse(decoded, merkle::sha256(&info_bytes))?;
+        // Torrents with only v2 hashes have no pieces and take their files from the file tree (BEP 52)
+        let v2_only = v2.is_some() && decoded.get("info").and_then(|info| info.get("pieces")).is_none();
+
+        // Piece Hashes
+        let mut piece_hashes: Vec<[u8; 20]> = Vec::new();
+        if !v2_only {
+            let pieces = decoded.lookup_bytes("info.pieces")?;
+            if pieces.len() % 20 != 0 {
+                return Err(InvalidTorrentFile::InvalidValue("info.pieces"));
             }
-            
-            // Check whether last pieces last block is of BLOCK_SIZE or not
-            if last_piece_length%(BLOCK_SIZE as u64) != 0 { 
-                piece_freq.last_mut().unwrap().blocks.push(
-                    Block {
-                        is_req: false, 
-                        length: (last_piece_length as u64)%(BLOCK_SIZE as u64), 
-                        offset: 0
-                    }
-                );
+            piece_hashes = pieces.chunks_exact(20).map(|hash| hash.try_into().unwrap()).collect();
+        }
+
+        let length = if let Some(v2) = v2.as_mut().filter(|_| v2_only) {
+            // Only the piece layers hash the pieces of larger files, and metadata fetched from peers does not have them
+            if !v2.has_piece_layers() {
+                return Err(InvalidTorrentFile::MissingPieceLayers);
             }
-    
+            let (v1_files, length) = v2.v1_layout(&name)?;
+            files = v1_files;
+            length
         }
+        else if decoded.get("info").and_then(|info| info.get("files")).is_some() {
+
+            // Length for multiple files
+            let mut length: u64 = 0;
+            let mut file_list = Vec::new();
+            // Raw path each decoded path came from
+            let mut raw_paths = HashMap::new();
 
-        let mut curr: u64 = 0;
+            for i in 0..decoded.lookup_list("info.files")?.len() {
+
+                let file_length = u64::try_from(decoded.lookup_int(&format!("info.files[{}].length", i))?)
+                    .map_err(|_| InvalidTorrentFile::InvalidValue("info.files.length"))?;
+                let path = decoder.lookup_path(decoded, &format!("info.files[{}]", i), "path")?;
+
+                // Escaped bytes could otherwise give the same name as a path holding a literal %XX
+                let raw = decoded.lookup(&format!("info.files[{}].path", i))?;
+                if raw_paths.insert(path.clone(), raw).is_some_and(|earlier| earlier != raw) {
+                    return Err(InvalidTorrentFile::InvalidValue("info.files.path"));
+                }
+
+                let attr = match decoded.lookup(&format!("info.files[{}].attr", i)) {
+                    Ok(_) => FileAttr::parse(decoded.lookup_bytes(&format!("info.files[{}].attr", i))?),
+                    Err(_) => FileAttr::default()
+                };
+                let symlink_path = if attr.symlink {
+                    Some(decoder.lookup_path(decoded, &format!("info.files[{}]", i), "symlink path")?)
+                }
+                else {
+                    None
+                };
+
+                length = length.checked_add(file_length).ok_or(InvalidTorrentFile::InvalidValue("info.files.length"))?;
+                file_list.push(TorrentFile { path, length: file_length, attr, symlink_path });
 
-        for piece in &mut piece_freq {
-            for block in &mut piece.blocks {
-                (*block).offset = curr;
-                curr += block.length;
             }
+            files = Some(file_list);
+            length
+
+        }
+        else {
+            // Length for single file
+            u64::try_from(decoded.lookup_int("info.length")?).map_err(|_| InvalidTorrentFile::InvalidValue("info.length"))?
+        };
+
+        // Every byte has to be covered by exactly the pieces listed, and piece indices are u32 on the wire
+        let piece_count = match &v2 {
+            Some(v2) if v2_only => v2.v1_pieces.len(),
+            _ => piece_hashes.len()
+        };
+        if length.div_ceil(piece_length) != piece_count as u64 || u32::try_from(piece_count).is_err() {
+            return Err(InvalidTorrentFile::InvalidValue("info.pieces"));
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&info_bytes);
+        let mut info_hash = hasher.digest().bytes();
+
+        match &mut v2 {
+            Some(v2) if v2_only => info_hash = v2.truncated_hash(),
+            Some(v2) => v2.align_with_v1(piece_length, &name, length, files.as_deref(), piece_hashes.len())?,
+            None => ()
+        }
+
+        // Optional keys of the info dict, ignored when they have the wrong type
+        let private = decoded.lookup_int("info.private").is_ok_and(|private| private == 1);
+        let source = decoded.lookup_bytes("info.source").ok().map(|bytes| decoder.decode(bytes));
+        let similar = decoded
+            .lookup_list("info.similar")
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|hash| hash.as_bytes()?.try_into().ok())
+            .collect();
+        let collections = decoded.lookup_list("info.collections").unwrap_or_default().iter().filter_map(text).collect();
+        let update_url = decoded.lookup_bytes("info.update-url").ok().and_then(|url| String::from_utf8(url.to_vec()).ok());
+        let originator = decoded.lookup_bytes("info.originator").ok().map(<[u8]>::to_vec);
+
+        // Optional top-level keys, those with the wrong type are kept in extra untouched
+        let mut extra = decoded.as_dict().cloned().unwrap_or_default();
+        for key in [b"announce".as_slice(), b"announce-list", b"info"] {
+            extra.remove(key);
+        }
+        let creation_date = take(&mut extra, "creation date", Element::as_int);
+        let comment = take(&mut extra, "comment", text);
+        let created_by = take(&mut extra, "created by", text);
+        let encoding = take(&mut extra, "encoding", string);
+        let url_list = take(&mut extra, "url-list", string_list).unwrap_or_default();
+        let http_seeds = take(&mut extra, "httpseeds", string_list).unwrap_or_default();
+        let nodes = take(&mut extra, "nodes", node_list).unwrap_or_default();
+
+        Ok(Metainfo {
+            announce, announce_list, name, piece_length, length, piece_hashes, files, info_hash, info_bytes, v2,
+            creation_date, comment, created_by, encoding, private, source, url_list, http_seeds, nodes, similar, collections,
+            update_url, originator, extra, warnings
+        })
+
+    }
+
+}
+
+impl Torrent {
+
+    /// Fresh download state with nothing downloaded and no peers
+    pub fn new(metainfo: Metainfo) -> Torrent {
+
+        let piece_freq = Torrent::build_piece_freq(metainfo.piece_count(), metainfo.piece_length, metainfo.length);
+        let have = Bitfield::new(metainfo.piece_count());
+
+        Torrent {
+            metainfo: Arc::new(metainfo),
+            peer_list: Arc::new(Mutex::new(VecDeque::new())),
+            peer_id: helpers::gen_random_id(),
+            piece_freq: Arc::new(Mutex::new(piece_freq)),
+            downloaded: Arc::new(Mutex::new(0)),
+            uploaded: Arc::new(Mutex::new(0)),
+            connections: Arc::new(Mutex::new(HashSet::new())),
+            have: Arc::new(Mutex::new(have))
         }
-    
-        piece_freq
+
+    }
+
+    // Function to build the piece frequency array used by download
+    // Blocks are only made for pieces being downloaded, so huge torrents do not need memory for all of them up front
+    fn build_piece_freq(piece_no: u32, piece_length: u64, length: u64) -> Vec<Piece> {
+        (0..piece_no as u64)
+            .map(|index| Piece { ref_no: 0, length: piece_length.min(length - index * piece_length), blocks: Vec::new() })
+            .collect()
     }
 
 }
 
+impl Piece {
+
+    /// Whether some block is not requested yet, a piece without blocks has not been picked
+    pub fn has_free_blocks(&self) -> bool {
+        self.blocks.is_empty() || self.blocks.iter().any(|block| !block.is_req)
+    }
+
+    /// Blocks of the piece, made when it is first picked, the last one may be shorter
+    pub fn blocks_mut(&mut self) -> &mut Vec<Block> {
+        if self.blocks.is_empty() {
+            let block_size = BLOCK_SIZE as u64;
+            self.blocks = (0..self.length.div_ceil(block_size))
+                .map(|j| Block { is_req: false, length: block_size.min(self.length - j * block_size), offset: j * block_size })
+                .collect();
+        }
+        &mut self.blocks
+    }
+
+}
+
+// Remove key from dict if parse accepts its value
+fn take<T>(dict: &mut BTreeMap<Vec<u8>, Element>, key: &str, parse: impl Fn(&Element) -> Option<T>) -> Option<T> {
+    let value = parse(dict.get(key.as_bytes())?)?;
+    dict.remove(key.as_bytes());
+    Some(value)
+}
+
+fn string(element: &Element) -> Option<String> {
+    element.as_bytes().map(|bytes| String::from_utf8_lossy(bytes).to_string())
+}
+
+// List of strings, or a single string
+fn string_list(element: &Element) -> Option<Vec<String>> {
+    match element {
+        Element::List(list) => list.iter().map(string).collect(),
+        _ => Some(vec![string(element)?])
+    }
+}
+
+// List of [host, port] pairs
+fn node_list(element: &Element) -> Option<Vec<(String, u16)>> {
+    element
+        .as_list()?
+        .iter()
+        .map(|node| match node.as_list()? {
+            [host, port] => Some((string(host)?, u16::try_from(port.as_int()?).ok()?)),
+            _ => None
+        })
+        .collect()
+}
+
+fn strings(list: &[String]) -> Element {
+    Element::List(list.iter().map(|s| Element::ByteString(s.as_bytes().to_vec())).collect())
+}
+
+/// Error returned when a torrent file can not be used
 #[derive(Debug)]
-pub struct InvalidTorrentFile {
-    case: i32
+pub enum InvalidTorrentFile {
+    // Not valid bencode
+    Decode(DecodeError),
+    // Required key missing or of the wrong type
+    Lookup(LookupError),
+    // Key present but its value is not usable
+    InvalidValue(&'static str),
+    // The v1 and v2 parts of a hybrid torrent describe different files
+    HybridMismatch(String),
+    // Torrent with only v2 hashes without the hashes of its pieces
+    MissingPieceLayers
 }
 
 impl fmt::Display for InvalidTorrentFile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Keys missing in torrent file {}", self.case)
+        match self {
+            InvalidTorrentFile::Decode(e) => write!(f, "Invalid torrent file: {}", e),
+            InvalidTorrentFile::Lookup(e) => write!(f, "Invalid torrent file: {}", e),
+            InvalidTorrentFile::InvalidValue(path) => write!(f, "Invalid torrent file: invalid value at path:{}", path),
+            InvalidTorrentFile::HybridMismatch(reason) => write!(f, "Invalid torrent file: v1 and v2 files disagree, {}", reason),
+            InvalidTorrentFile::MissingPieceLayers => write!(f, "Invalid torrent file: piece layers missing, torrents with only v2 hashes can not be downloaded from a magnet link")
+        }
+    }
+}
+
+impl std::error::Error for InvalidTorrentFile {}
+
+impl From<DecodeError> for InvalidTorrentFile {
+    fn from(e: DecodeError) -> Self {
+        InvalidTorrentFile::Decode(e)
+    }
+}
+
+impl From<LookupError> for InvalidTorrentFile {
+    fn from(e: LookupError) -> Self {
+        InvalidTorrentFile::Lookup(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1_smol::Sha1;
+    use crate::{bencoded_parser::{Bencode, Element}, merkle};
+    use super::{FileAttr, InvalidTorrentFile, Metainfo, Torrent, TorrentFile};
+
+    #[test]
+    fn missing_file_length() {
+        match Metainfo::from_bytes(b"d8:announce3:url4:infod5:filesld4:pathl1:aeee4:name1:x12:piece lengthi16384e6:pieces0:ee") {
+            Err(InvalidTorrentFile::Lookup(e)) => assert_eq!(e.to_string(), "Missing value at path:info.files[0].length"),
+            _ => panic!("expected missing length")
+        }
+    }
+
+    #[test]
+    fn negative_lengths() {
+        let single = Metainfo::from_bytes(b"d8:announce3:url4:infod6:lengthi-1e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee");
+        assert!(matches!(single, Err(InvalidTorrentFile::InvalidValue("info.length"))));
+
+        let multi = Metainfo::from_bytes(b"d8:announce3:url4:infod5:filesl\
+            d6:lengthi-1e4:pathl1:aeed6:lengthi3e4:pathl1:beee\
+            4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee");
+        assert!(matches!(multi, Err(InvalidTorrentFile::InvalidValue("info.files.length"))));
+
+        // Lengths that do not add up within u64
+        let overflow = Metainfo::from_bytes(b"d8:announce3:url4:infod5:filesl\
+            d6:lengthi9223372036854775807e4:pathl1:aeed6:lengthi9223372036854775807e4:pathl1:bee\
+            d6:lengthi9223372036854775807e4:pathl1:ceee4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee");
+        assert!(matches!(overflow, Err(InvalidTorrentFile::InvalidValue("info.files.length"))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn hybrid_from_info() {
+        // Hybrid single file torrent of two pieces, as fetched from peers without piece layers
+        let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let root = merkle::file_root(&merkle::block_hashes(&data), 16384).unwrap();
+        let pieces: Vec<u8> = data.chunks(16384).flat_map(|piece| {
+            let mut hasher = Sha1::new();
+            hasher.update(piece);
+            hasher.digest().bytes()
+        }).collect();
+        let dict = |entries: Vec<(&[u8], Element)>| Element::Dict(entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect());
+
+        let file = dict(vec![(b"", dict(vec![(b"length", Element::Integer(20000)), (b"pieces root", Element::ByteString(root.to_vec()))]))]);
+        let info = dict(vec![
+            (b"file tree", dict(vec![(b"x", file)])),
+            (b"length", Element::Integer(20000)),
+            (b"meta version", Element::Integer(2)),
+            (b"name", Element::ByteString(b"x".to_vec())),
+            (b"piece length", Element::Integer(16384)),
+            (b"pieces", Element::ByteString(pieces))
+        ]);
+
+        // Pieces of the file are checked against the v1 hashes alone
+        let metainfo = Metainfo::from_info(Bencode::encode(&info), vec![vec!["udp://t".to_string()]]).unwrap();
+        assert!(metainfo.verify_piece(0, &data[..16384]));
+        assert!(metainfo.verify_piece(1, &data[16384..]));
+        assert!(!metainfo.verify_piece(1, &data[..3616]));
+    }
+
+    #[test]
+    fn v2_only() {
+        // Two piece file and a small file, described only by the file tree and piece layers
+        let big: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let leaves = merkle::block_hashes(&big);
+        let root = merkle::file_root(&leaves, 16384).unwrap();
+        let layer: Vec<u8> = leaves.iter().flat_map(|leaf| merkle::piece_hash(std::slice::from_ref(leaf), 16384)).collect();
+        let small_root = merkle::file_root(&merkle::block_hashes(b"hello"), 16384).unwrap();
+
+        let dict = |entries: Vec<(&[u8], Element)>| Element::Dict(entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect());
+        let file = |length: i64, root: &[u8]| {
+            dict(vec![(b"", dict(vec![(b"length", Element::Integer(length)), (b"pieces root", Element::ByteString(root.to_vec()))]))])
+        };
+        let info = dict(vec![
+            (b"file tree", dict(vec![(b"big", file(20000, &root)), (b"small", file(5, &small_root))])),
+            (b"meta version", Element::Integer(2)),
+            (b"name", Element::ByteString(b"v2".to_vec())),
+            (b"piece length", Element::Integer(16384))
+        ]);
+
+        // Metadata from peers has no piece layers, so nothing of the big file could be verified
+        let from_info = Metainfo::from_info(Bencode::encode(&info), vec![vec!["udp://t".to_string()]]);
+        assert!(matches!(from_info, Err(InvalidTorrentFile::MissingPieceLayers)));
+
+        let torrent = dict(vec![
+            (b"announce", Element::ByteString(b"url".to_vec())),
+            (b"info", info),
+            (b"piece layers", dict(vec![(root.as_slice(), Element::ByteString(layer))]))
+        ]);
+
+        let metainfo = Metainfo::from_bytes(&Bencode::encode(&torrent)).unwrap();
+        assert!(metainfo.piece_hashes.is_empty());
+        assert_eq!(metainfo.info_hashes(), vec![metainfo.v2.as_ref().unwrap().truncated_hash()]);
+
+        // The small file starts on a piece boundary after padding
+        let files = metainfo.files.as_ref().unwrap();
+        assert_eq!(files.iter().map(|file| (file.length, file.attr.padding)).collect::<Vec<_>>(), vec![(20000, false), (12768, true), (5, false)]);
+        assert_eq!(metainfo.length, 32773);
+        assert_eq!(metainfo.piece_count(), 3);
+
+        let mut last = big[16384..].to_vec();
+        last.resize(16384, 0);
+        assert!(metainfo.verify_piece(0, &big[..16384]));
+        assert!(metainfo.verify_piece(1, &last));
+        assert!(metainfo.verify_piece(2, b"hello"));
+        assert!(!metainfo.verify_piece(2, b"world"));
+        assert!(!metainfo.verify_piece(1, &big[..16384]));
+        assert!(!metainfo.verify_piece(3, b"hello"));
+        assert_eq!(Torrent::new(metainfo).piece_freq.try_lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn file_attributes() {
+        let metainfo = Metainfo::from_bytes(b"d8:announce3:url4:infod5:filesl\
+            d6:lengthi3e4:pathl1:aee\
+            d4:attr1:p6:lengthi5e4:pathl4:.pad1:5ee\
+            d4:attr2:xh6:lengthi1e4:pathl3:bin1:bee\
+            d4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl3:bin1:bee\
+            e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").unwrap();
+
+        let files = metainfo.files.unwrap();
+        assert_eq!(files[0].attr, FileAttr::default());
+        assert!(files[1].attr.padding);
+        assert_eq!(files[2].attr, FileAttr { executable: true, hidden: true, ..FileAttr::default() });
+        assert_eq!(files[3], TorrentFile {
+            path: vec!["link".to_string()],
+            length: 0,
+            attr: FileAttr { symlink: true, ..FileAttr::default() },
+            symlink_path: Some(vec!["bin".to_string(), "b".to_string()])
+        });
+    }
+
+    #[test]
+    fn legacy_names() {
+        // Shift-JIS name with a UTF-8 copy, Shift-JIS path without one, and a path not in the declared encoding
+        let metainfo = Metainfo::from_bytes(b"d8:announce3:url8:encoding9:Shift_JIS4:infod5:filesl\
+            d6:lengthi1e4:pathl4:\x93\xfa\x96\x7bee\
+            d6:lengthi1e4:pathl1:\x82e10:path.utf-8l2:okee\
+            d6:lengthi1e4:pathl1:\x82eee\
+            4:name2:\x93\xfa10:name.utf-84:\xe6\x97\xa5x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").unwrap();
+
+        assert_eq!(metainfo.name, "日x");
+        let paths: Vec<_> = metainfo.files.unwrap().into_iter().map(|file| file.path).collect();
+        assert_eq!(paths, vec![vec!["日本".to_string()], vec!["ok".to_string()], vec!["%82".to_string()]]);
+
+        // Escaped byte and a literal %FF give the same name
+        let colliding = Metainfo::from_bytes(b"d8:announce3:url4:infod5:filesl\
+            d6:lengthi1e4:pathl3:%FFee\
+            d6:lengthi1e4:pathl1:\xffee\
+            e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee");
+        assert!(matches!(colliding, Err(InvalidTorrentFile::InvalidValue("info.files.path"))));
+    }
+
+    #[test]
+    fn legacy_hybrid_name() {
+        // Hybrid single file torrent whose v1 name is Latin-1, the file tree has it in UTF-8
+        let data = b"hello";
+        let root = merkle::file_root(&merkle::block_hashes(data), 16384).unwrap();
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        let dict = |entries: Vec<(&[u8], Element)>| Element::Dict(entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect());
+
+        let file = dict(vec![(b"", dict(vec![(b"length", Element::Integer(5)), (b"pieces root", Element::ByteString(root.to_vec()))]))]);
+        let info = dict(vec![
+            (b"file tree", dict(vec![("café".as_bytes(), file)])),
+            (b"length", Element::Integer(5)),
+            (b"meta version", Element::Integer(2)),
+            (b"name", Element::ByteString(b"caf\xe9".to_vec())),
+            (b"piece length", Element::Integer(16384)),
+            (b"pieces", Element::ByteString(hasher.digest().bytes().to_vec()))
+        ]);
+        let torrent = dict(vec![
+            (b"announce", Element::ByteString(b"url".to_vec())),
+            (b"encoding", Element::ByteString(b"latin1".to_vec())),
+            (b"info", info)
+        ]);
+
+        let metainfo = Metainfo::from_bytes(&Bencode::encode(&torrent)).unwrap();
+        assert_eq!(metainfo.name, "café");
+        assert!(metainfo.v2.unwrap().verify_v1_piece(0, data));
+    }
+
+    #[test]
+    fn optional_fields() {
+        let metainfo = Metainfo::from_bytes(b"d8:announce3:url7:comment2:hi10:created by4:test13:creation datei1700000000e\
+            8:encoding5:UTF-89:httpseedsl7:http://e4:infod11:collectionsl3:onee6:lengthi1e4:name1:x10:originator3:key12:piece lengthi16384e\
+            6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e7:similarl20:bbbbbbbbbbbbbbbbbbbb1:xe6:source4:team10:update-url11:http://feede5:nodesl\
+            l9:127.0.0.1i6881eel4:hosti70000eee8:url-list7:http://e").unwrap();
+
+        assert_eq!(metainfo.creation_date, Some(1700000000));
+        assert_eq!(metainfo.comment.as_deref(), Some("hi"));
+        assert_eq!(metainfo.created_by.as_deref(), Some("test"));
+        assert_eq!(metainfo.encoding.as_deref(), Some("UTF-8"));
+        assert!(metainfo.private);
+        assert_eq!(metainfo.source.as_deref(), Some("team"));
+        assert_eq!(metainfo.url_list, vec!["http://"]);
+        assert_eq!(metainfo.http_seeds, vec!["http://"]);
+        assert_eq!(metainfo.similar, vec![[b'b'; 20]]);
+        assert_eq!(metainfo.collections, vec!["one"]);
+        assert_eq!(metainfo.update_url.as_deref(), Some("http://feed"));
+        assert_eq!(metainfo.originator.as_deref(), Some(b"key".as_slice()));
+
+        // Port out of range, so nodes is left as it was
+        assert!(metainfo.nodes.is_empty());
+        assert!(metainfo.extra.contains_key(b"nodes".as_slice()));
+        assert_eq!(metainfo.magnet().to_string(), "magnet:?xt=urn:btih:".to_string() + &hex::encode(metainfo.info_hash) + "&dn=x&tr=url&ws=http%3A%2F%2F");
+
+        // Single url-list strings are written back as lists
+        let written = Metainfo::from_bytes(&metainfo.to_bytes()).unwrap();
+        assert_eq!(written, Metainfo { warnings: written.warnings.clone(), ..metainfo });
+    }
+
+    #[test]
+    fn more_than_u16_pieces() {
+        // 70000 pieces of 16 KiB, the last one a byte short
+        let pieces = 70000;
+        let length = pieces * 16384 - 1;
+        let mut buf = format!("d8:announce3:url4:infod6:lengthi{}e4:name1:x12:piece lengthi16384e6:pieces{}:", length, pieces * 20).into_bytes();
+        buf.extend(vec![b'a'; pieces as usize * 20]);
+        buf.extend(b"ee");
+
+        let torrent = Torrent::new(Metainfo::from_bytes(&buf).unwrap());
+        assert_eq!(torrent.metainfo.piece_count(), 70000);
+        assert_eq!(torrent.have.try_lock().unwrap().len(), 70000);
+
+        // No blocks until a piece is picked
+        let mut freq = torrent.piece_freq.try_lock().unwrap();
+        assert_eq!(freq.len(), 70000);
+        assert!(freq.iter().all(|piece| piece.blocks.is_empty() && piece.has_free_blocks()));
+        let last = freq.last_mut().unwrap();
+        assert_eq!(last.length, 16383);
+        assert_eq!(last.blocks_mut().iter().map(|block| (block.offset, block.length)).collect::<Vec<_>>(), vec![(0, 16383)]);
+    }
+
+    #[test]
+    fn metainfo_round_trip() {
+        // Non-canonical info dict and an unknown top-level key
+        let buf = b"d8:announce3:url7:comment2:hi4:infod6:lengthi40000e4:name1:x12:piece lengthi32768e6:pieces40:\
+            aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb1:ai1eee";
+        let metainfo = Metainfo::from_reader(&mut buf.as_slice()).unwrap();
+        assert_eq!(metainfo.warnings.len(), 1);
+        assert_eq!(metainfo.to_bytes(), buf);
+        assert_eq!(Metainfo::from_bytes(&metainfo.to_bytes()).unwrap(), metainfo);
+
+        // Magnet metadata gets the trackers as its only tier
+        let from_info = Metainfo::from_info(metainfo.info_bytes.clone(), vec![vec!["udp://t".to_string()]]).unwrap();
+        assert_eq!(from_info.info_hash, metainfo.info_hash);
+        assert_eq!(from_info.announce_list, Some(vec![vec!["udp://t".to_string()]]));
+
+        // Pieces are split into whole blocks, the last piece keeps what is left
+        let torrent = Torrent::new(metainfo);
+        let mut freq = torrent.piece_freq.try_lock().unwrap();
+        let blocks: Vec<_> = freq.iter_mut().map(|piece| piece.blocks_mut().iter().map(|block| (block.offset, block.length)).collect::<Vec<_>>()).collect();
+        assert_eq!(blocks, vec![vec![(0, 16384), (16384, 16384)], vec![(0, 7232)]]);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt::Write;
+use encoding_rs::{Encoding, UTF_8};
+use crate::bencoded_parser::Element;
+use super::InvalidTorrentFile;
+
+/// Turns the strings of a torrent into text using its `encoding` key
+/// Torrents made on legacy systems hold names in encodings such as Shift-JIS or Latin-1
+#[derive(Debug, Clone, Copy)]
+pub struct TextDecoder {
+    // None when the torrent declares no encoding, or UTF-8, or one that is not known
+    encoding: Option<&'static Encoding>
+}
+
+impl TextDecoder {
+
+    pub fn new(label: Option<&[u8]>) -> TextDecoder {
+        let encoding = label.and_then(Encoding::for_label).filter(|&encoding| encoding != UTF_8);
+        TextDecoder { encoding }
+    }
+
+    ///Decode a string of the torrent
+    ///UTF-8 is kept as is, then the declared encoding is tried, anything else gets the lossless fallback
+    pub fn decode(&self, bytes: &[u8]) -> String {
+
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            return text.to_string();
+        }
+        let transcoded = self.encoding.and_then(|encoding| encoding.decode_without_bom_handling_and_without_replacement(bytes));
+        match transcoded {
+            Some(text) => text.into_owned(),
+            None => escape_bytes(bytes)
+        }
+
+    }
+
+    ///Byte string at key of the dict at path `at`, `key.utf-8` is used instead when it is present and valid
+    pub fn lookup_string(&self, decoded: &Element, at: &str, key: &str) -> Result<String, InvalidTorrentFile> {
+        let utf8 = decoded.lookup(at)?.get(&format!("{}.utf-8", key)).and_then(Element::as_str);
+        match utf8 {
+            Some(text) => Ok(text.to_string()),
+            None => Ok(self.decode(decoded.lookup_bytes(&format!("{}.{}", at, key))?))
+        }
+    }
+
+    ///Path list at key of the dict at path `at`, `key.utf-8` is used instead when every component of it is valid
+    pub fn lookup_path(&self, decoded: &Element, at: &str, key: &str) -> Result<Vec<String>, InvalidTorrentFile> {
+
+        let utf8 = decoded
+            .lookup(at)?
+            .get(&format!("{}.utf-8", key))
+            .and_then(Element::as_list)
+            .and_then(|list| list.iter().map(|part| part.as_str().map(str::to_string)).collect::<Option<Vec<_>>>());
+        if let Some(path) = utf8 {
+            return Ok(path);
+        }
+
+        let path = format!("{}.{}", at, key);
+        let mut parts = Vec::new();
+        for j in 0..decoded.lookup_list(&path)?.len() {
+            parts.push(self.decode(decoded.lookup_bytes(&format!("{}[{}]", path, j))?));
+        }
+        Ok(parts)
+
+    }
+
+}
+
+///Lossless stand-in for bytes that are not text
+///Valid UTF-8 runs are kept, other bytes and `%` become `%XX`, so names stay safe to put on disk and the bytes can be recovered
+pub fn escape_bytes(bytes: &[u8]) -> String {
+
+    let mut escaped = String::with_capacity(bytes.len() * 3);
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '%' {
+                escaped.push_str("%25");
+            }
+            else {
+                escaped.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(escaped, "%{:02X}", byte).unwrap();
+        }
+    }
+    escaped
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_bytes, TextDecoder};
+
+    #[test]
+    fn decode_legacy_text() {
+        let shift_jis = TextDecoder::new(Some(b"Shift_JIS"));
+        assert_eq!(shift_jis.decode(b"\x93\xfa\x96\x7b.txt"), "日本.txt");
+        assert_eq!(shift_jis.decode("ascii and ü".as_bytes()), "ascii and ü");
+        assert_eq!(TextDecoder::new(Some(b"latin1")).decode(b"caf\xe9"), "café");
+
+        // No usable encoding, bytes are escaped
+        assert_eq!(TextDecoder::new(None).decode(b"caf\xe9"), "caf%E9");
+        assert_eq!(TextDecoder::new(Some(b"no such encoding")).decode(b"100%\xff"), "100%25%FF");
+        assert_eq!(shift_jis.decode(b"\x82"), "%82");
+        assert_eq!(escape_bytes("é\u{0}".as_bytes()), "é\u{0}");
+    }
+}
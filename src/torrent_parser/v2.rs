@@ -0,0 +1,448 @@
+use std::{collections::BTreeMap, io::{self, Read}};
+use crate::{
+    bencoded_parser::{Element, LookupError, LookupErrorKind},
+    merkle::{self, Hash, MERKLE_BLOCK_SIZE}
+};
+use super::{FileAttr, InvalidTorrentFile, TorrentFile};
+
+/// v2 description of a torrent (BEP 52)
+#[derive(Debug, Clone, PartialEq)]
+pub struct V2Info {
+    // SHA-256 of the info dict, peers and trackers use the first 20 bytes
+    pub info_hash: [u8; 32],
+    pub piece_length: u64,
+    // In file tree order
+    pub files: Vec<V2File>,
+    // For hybrid torrents, the v2 piece holding the data of each v1 piece
+    pub v1_pieces: Vec<Option<V2Piece>>
+}
+
+/// Part of a v2 file covered by a v1 piece of a hybrid torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct V2Piece {
+    pub file: usize,
+    // Piece index within the file
+    pub piece: u64,
+    // Bytes of file data at the start of the v1 piece, the rest is padding
+    pub length: u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+    // None for empty files
+    pub pieces_root: Option<Hash>,
+    // Hashes of the pieces of files larger than one piece, None when the torrent did not include them
+    pub piece_layer: Option<Vec<Hash>>
+}
+
+impl V2File {
+
+    pub fn piece_count(&self, piece_length: u64) -> u64 {
+        self.length.div_ceil(piece_length)
+    }
+
+}
+
+impl V2Info {
+
+    ///Parse the v2 parts of a decoded torrent
+    ///Returns None when the info dict has no `meta version` 2, `info_hash` is the SHA-256 of the raw info dict
+    pub fn parse(decoded: &Element, info_hash: [u8; 32]) -> Result<Option<V2Info>, InvalidTorrentFile> {
+
+        match decoded.get("info").and_then(|info| info.get("meta version")) {
+            None => return Ok(None),
+            Some(version) if version.as_int() == Some(2) => {},
+            Some(_) => return Err(InvalidTorrentFile::InvalidValue("info.meta version"))
+        }
+
+        // Piece length must be a power of two and at least one merkle block
+        let piece_length = decoded.lookup_int("info.piece length")?;
+        if piece_length < MERKLE_BLOCK_SIZE as i64 || !(piece_length as u64).is_power_of_two() {
+            return Err(InvalidTorrentFile::InvalidValue("info.piece length"));
+        }
+        let piece_length = piece_length as u64;
+
+        let mut files = Vec::new();
+        walk_file_tree(decoded.lookup_dict("info.file tree")?, &mut Vec::new(), &mut files)?;
+        if files.is_empty() {
+            return Err(InvalidTorrentFile::InvalidValue("info.file tree"));
+        }
+
+        // Piece layers are not part of the info dict, so metadata fetched from peers does not have them
+        let layers = match decoded.get("piece layers") {
+            Some(_) => Some(decoded.lookup_dict("piece layers")?),
+            None => None
+        };
+
+        if let Some(layers) = layers {
+            for file in files.iter_mut().filter(|file| file.length > piece_length) {
+                let root = file.pieces_root.expect("non-empty files have a root");
+                let layer = layers
+                    .get(root.as_slice())
+                    .ok_or_else(|| LookupError { path: format!("piece layers.{}", hex::encode(root)), kind: LookupErrorKind::Missing })?
+                    .as_bytes()
+                    .ok_or(InvalidTorrentFile::InvalidValue("piece layers"))?;
+
+                // One hash per piece, and together they must give the pieces root
+                if layer.len() as u64 != 32 * file.piece_count(piece_length) {
+                    return Err(InvalidTorrentFile::InvalidValue("piece layers"));
+                }
+                let hashes: Vec<Hash> = layer.chunks_exact(32).map(|hash| hash.try_into().unwrap()).collect();
+                if merkle::layer_root(&hashes, piece_length) != root {
+                    return Err(InvalidTorrentFile::InvalidValue("piece layers"));
+                }
+                file.piece_layer = Some(hashes);
+            }
+        }
+
+        Ok(Some(V2Info { info_hash, piece_length, files, v1_pieces: Vec::new() }))
+
+    }
+
+    /// Whether every file larger than one piece has its piece layer, without them only whole files can be checked
+    pub fn has_piece_layers(&self) -> bool {
+        (0..self.files.len()).all(|index| self.has_piece_hash(index))
+    }
+
+    ///Lay out the files of a torrent with only v2 hashes as a hybrid torrent would, each file starting on a piece boundary
+    ///Returns the v1 file list with padding between files, None for a single file named like the torrent, and the total length
+    pub fn v1_layout(&mut self, name: &str) -> Result<(Option<Vec<TorrentFile>>, u64), InvalidTorrentFile> {
+
+        if let [file] = self.files.as_slice() {
+            if file.path == [name] {
+                let length = file.length;
+                self.align_with_v1(self.piece_length, name, length, None, length.div_ceil(self.piece_length) as usize)?;
+                return Ok((None, length));
+            }
+        }
+
+        let too_long = || InvalidTorrentFile::InvalidValue("info.file tree");
+        let mut files = Vec::new();
+        let mut length: u64 = 0;
+        for file in &self.files {
+            let pad = length.checked_next_multiple_of(self.piece_length).ok_or_else(too_long)? - length;
+            if file.length > 0 && pad > 0 {
+                let attr = FileAttr { padding: true, ..FileAttr::default() };
+                files.push(TorrentFile { path: vec![".pad".to_string(), pad.to_string()], length: pad, attr, symlink_path: None });
+                length += pad;
+            }
+            files.push(TorrentFile { path: file.path.clone(), length: file.length, attr: FileAttr::default(), symlink_path: None });
+            length = length.checked_add(file.length).ok_or_else(too_long)?;
+        }
+
+        self.align_with_v1(self.piece_length, name, length, Some(&files), length.div_ceil(self.piece_length) as usize)?;
+        Ok((Some(files), length))
+
+    }
+
+    ///Check that the v1 description of a hybrid torrent matches this one and map v1 pieces onto v2 pieces
+    ///Takes the v1 fields as parsed, files is None for a single file torrent holding length bytes under name
+    ///Files other than padding must appear in the same order with the same paths and lengths, each starting on a piece boundary
+    pub fn align_with_v1(&mut self, piece_length: u64, name: &str, length: u64, files: Option<&[TorrentFile]>, piece_count: usize) -> Result<(), InvalidTorrentFile> {
+
+        let mismatch = |reason: String| InvalidTorrentFile::HybridMismatch(reason);
+
+        if piece_length != self.piece_length {
+            return Err(mismatch("piece length".to_string()));
+        }
+
+        // v1 files as path, length and whether it is padding
+        let v1_files: Vec<(Vec<String>, u64, bool)> = match files {
+            Some(files) => files.iter().map(|file| (file.path.clone(), file.length, file.attr.padding)).collect(),
+            None => vec![(vec![name.to_string()], length, false)]
+        };
+
+        // Piece indices are u32 on the wire, checked before allocating a slot for each
+        if u32::try_from(piece_count).is_err() {
+            return Err(InvalidTorrentFile::InvalidValue("info.pieces"));
+        }
+        let mut v1_pieces = vec![None; piece_count];
+        let mut v2_files = self.files.iter().enumerate();
+        let mut offset: u64 = 0;
+
+        for (path, length, padding) in v1_files {
+
+            if padding {
+                offset = offset.checked_add(length).ok_or_else(|| mismatch("length".to_string()))?;
+                continue;
+            }
+
+            let (index, file) = v2_files.next().ok_or_else(|| mismatch(format!("{} is not in the file tree", path.join("/"))))?;
+            if file.path != path || file.length != length {
+                return Err(mismatch(format!("{} does not match {}", path.join("/"), file.path.join("/"))));
+            }
+            if length > 0 && !offset.is_multiple_of(self.piece_length) {
+                return Err(mismatch(format!("{} does not start on a piece boundary", file.path.join("/"))));
+            }
+
+            for piece in 0..file.piece_count(self.piece_length) {
+                let v1_index = (offset / self.piece_length + piece) as usize;
+                let slot = v1_pieces.get_mut(v1_index).ok_or_else(|| mismatch("piece count".to_string()))?;
+                *slot = Some(V2Piece { file: index, piece, length: self.piece_length.min(length - piece * self.piece_length) });
+            }
+            offset = offset.checked_add(length).ok_or_else(|| mismatch("length".to_string()))?;
+
+        }
+
+        if let Some((_, file)) = v2_files.next() {
+            return Err(mismatch(format!("{} is missing from the v1 file list", file.path.join("/"))));
+        }
+        if offset.div_ceil(self.piece_length) != piece_count as u64 {
+            return Err(mismatch("piece count".to_string()));
+        }
+
+        self.v1_pieces = v1_pieces;
+        Ok(())
+
+    }
+
+    ///Verify a v1 piece against the v2 hashes
+    ///True when v2 does not cover it or has no hash for it, as in metadata fetched from peers, so only the v1 hash counts
+    pub fn verify_v1_piece(&self, index: u32, data: &[u8]) -> bool {
+        match self.v1_pieces.get(index as usize).copied().flatten() {
+            Some(piece) if !self.has_piece_hash(piece.file) => true,
+            Some(piece) => (piece.length as usize) <= data.len() && self.verify_piece(piece.file, piece.piece, &data[..piece.length as usize]),
+            None => true
+        }
+    }
+
+    // Files that fit in one piece are hashed by their root, larger ones by their piece layer
+    fn has_piece_hash(&self, file_index: usize) -> bool {
+        self.files.get(file_index).is_some_and(|file| file.length <= self.piece_length || file.piece_layer.is_some())
+    }
+
+    /// Info hash as used in handshakes and announces
+    pub fn truncated_hash(&self) -> [u8; 20] {
+        self.info_hash[..20].try_into().unwrap()
+    }
+
+    ///Verify one piece of a file
+    ///Pieces never span files in v2, the last piece of a file may be shorter. False if the hash is unknown
+    pub fn verify_piece(&self, file_index: usize, piece_index: u64, data: &[u8]) -> bool {
+
+        let Some(file) = self.files.get(file_index) else {
+            return false;
+        };
+        let leaves = merkle::block_hashes(data);
+
+        // Files that fit in one piece are checked against the root directly
+        if file.length <= self.piece_length {
+            return piece_index == 0 && data.len() as u64 == file.length && merkle::file_root(&leaves, self.piece_length) == file.pieces_root;
+        }
+
+        match &file.piece_layer {
+            Some(layer) => layer
+                .get(piece_index as usize)
+                .is_some_and(|hash| *hash == merkle::piece_hash(&leaves, self.piece_length)),
+            None => false
+        }
+
+    }
+
+    /// Verify the whole content of a file against its pieces root
+    pub fn verify_file<R: Read>(&self, file_index: usize, reader: &mut R) -> io::Result<bool> {
+
+        let Some(file) = self.files.get(file_index) else {
+            return Ok(false);
+        };
+
+        let leaves = merkle::read_block_hashes(&mut reader.take(file.length))?;
+        Ok(merkle::file_root(&leaves, self.piece_length) == file.pieces_root)
+
+    }
+
+}
+
+// Collect files of a file tree, a file is a dict with an empty key holding its length and pieces root
+fn walk_file_tree(node: &BTreeMap<Vec<u8>, Element>, path: &mut Vec<String>, files: &mut Vec<V2File>) -> Result<(), InvalidTorrentFile> {
+
+    let invalid = || InvalidTorrentFile::InvalidValue("info.file tree");
+
+    for (name, child) in node {
+
+        if name.is_empty() {
+            if path.is_empty() {
+                return Err(invalid());
+            }
+            let length = child.get("length").and_then(Element::as_int).filter(|length| *length >= 0).ok_or_else(invalid)? as u64;
+            let pieces_root = match child.get("pieces root").and_then(Element::as_bytes) {
+                Some(root) => Some(Hash::try_from(root).map_err(|_| invalid())?),
+                None => None
+            };
+            if (length > 0) != pieces_root.is_some() {
+                return Err(invalid());
+            }
+            files.push(V2File { path: path.clone(), length, pieces_root, piece_layer: None });
+            continue;
+        }
+
+        let name = std::str::from_utf8(name).map_err(|_| invalid())?;
+        let child = child.as_dict().ok_or_else(invalid)?;
+        path.push(name.to_string());
+        walk_file_tree(child, path, files)?;
+        path.pop();
+
+    }
+
+    Ok(())
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::{bencoded_parser::Element, merkle::{self, MERKLE_BLOCK_SIZE}};
+    use super::{FileAttr, InvalidTorrentFile, TorrentFile, V2Info, V2Piece};
+
+    const PIECE_LENGTH: u64 = 2 * MERKLE_BLOCK_SIZE as u64;
+
+    fn bytes(s: &[u8]) -> Element {
+        Element::ByteString(s.to_vec())
+    }
+
+    fn dict(entries: Vec<(&[u8], Element)>) -> Element {
+        Element::Dict(entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect::<BTreeMap<_, _>>())
+    }
+
+    fn file_entry(data: &[u8]) -> Element {
+        let mut entry = vec![(b"length".as_slice(), Element::Integer(data.len() as i64))];
+        if let Some(root) = merkle::file_root(&merkle::block_hashes(data), PIECE_LENGTH) {
+            entry.push((b"pieces root".as_slice(), bytes(&root)));
+        }
+        dict(vec![(b"", dict(entry))])
+    }
+
+    // Torrent with a three piece file in a directory, a small file and an empty file
+    fn sample(big: &[u8], small: &[u8], with_layers: bool) -> Element {
+        let tree = dict(vec![
+            (b"dir", dict(vec![(b"big", file_entry(big))])),
+            (b"empty", file_entry(b"")),
+            (b"small", file_entry(small))
+        ]);
+        let info = dict(vec![
+            (b"file tree", tree),
+            (b"meta version", Element::Integer(2)),
+            (b"name", bytes(b"sample")),
+            (b"piece length", Element::Integer(PIECE_LENGTH as i64))
+        ]);
+
+        let mut torrent = vec![(b"info".as_slice(), info)];
+        if with_layers {
+            let leaves = merkle::block_hashes(big);
+            let layer: Vec<u8> = leaves.chunks(2).flat_map(|piece| merkle::piece_hash(piece, PIECE_LENGTH)).collect();
+            let root = merkle::file_root(&leaves, PIECE_LENGTH).unwrap();
+            torrent.push((b"piece layers", dict(vec![(root.as_slice(), bytes(&layer))])));
+        }
+        dict(torrent)
+    }
+
+    #[test]
+    fn huge_file_tree() {
+        let file = |length: u64| super::V2File { path: vec![length.to_string()], length, pieces_root: Some([1; 32]), piece_layer: Some(Vec::new()) };
+        let mut info = V2Info { info_hash: [0; 32], piece_length: PIECE_LENGTH, files: vec![file(1 << 63), file(1 << 63)], v1_pieces: Vec::new() };
+
+        // Lengths past u64 and more pieces than fit in u32 are refused before anything is allocated
+        assert!(matches!(info.v1_layout("x"), Err(InvalidTorrentFile::InvalidValue("info.file tree"))));
+        info.files.pop();
+        assert!(matches!(info.v1_layout("x"), Err(InvalidTorrentFile::InvalidValue("info.pieces"))));
+    }
+
+    #[test]
+    fn parse_and_verify() {
+        let big: Vec<u8> = (0..MERKLE_BLOCK_SIZE * 5 + 3).map(|i| (i % 241) as u8).collect();
+        let small = b"small file".to_vec();
+
+        let info = V2Info::parse(&sample(&big, &small, true), [7; 32]).unwrap().unwrap();
+        assert_eq!(info.truncated_hash(), [7; 20]);
+        let paths: Vec<_> = info.files.iter().map(|file| file.path.join("/")).collect();
+        assert_eq!(paths, vec!["dir/big", "empty", "small"]);
+        assert_eq!(info.files[0].piece_layer.as_ref().unwrap().len(), 3);
+
+        for (i, piece) in big.chunks(PIECE_LENGTH as usize).enumerate() {
+            assert!(info.verify_piece(0, i as u64, piece));
+        }
+        assert!(!info.verify_piece(0, 1, &big[..PIECE_LENGTH as usize]));
+        assert!(info.verify_piece(2, 0, &small));
+        assert!(!info.verify_piece(2, 0, b"other file"));
+
+        assert!(info.verify_file(0, &mut big.as_slice()).unwrap());
+        assert!(info.verify_file(1, &mut [].as_slice()).unwrap());
+        assert!(!info.verify_file(0, &mut &big[..big.len() - 1]).unwrap());
+
+        // Without piece layers whole files can still be verified
+        let info = V2Info::parse(&sample(&big, &small, false), [7; 32]).unwrap().unwrap();
+        assert!(info.files[0].piece_layer.is_none());
+        assert!(info.verify_file(0, &mut big.as_slice()).unwrap());
+    }
+
+    fn v1_file(path: &[&str], length: usize, padding: bool) -> TorrentFile {
+        TorrentFile {
+            path: path.iter().map(|part| part.to_string()).collect(),
+            length: length as u64,
+            attr: FileAttr { padding, ..FileAttr::default() },
+            symlink_path: None
+        }
+    }
+
+    #[test]
+    fn hybrid_alignment() {
+        let big: Vec<u8> = (0..MERKLE_BLOCK_SIZE * 5 + 3).map(|i| (i % 241) as u8).collect();
+        let small = b"small file".to_vec();
+        let pad = 3 * PIECE_LENGTH as usize - big.len();
+        let total = (3 * PIECE_LENGTH) as usize + small.len();
+
+        let files = vec![
+            v1_file(&["dir", "big"], big.len(), false),
+            v1_file(&[".pad", "16381"], pad, true),
+            v1_file(&["empty"], 0, false),
+            v1_file(&["small"], small.len(), false)
+        ];
+        let mut info = V2Info::parse(&sample(&big, &small, true), [0; 32]).unwrap().unwrap();
+        let mut align = |files: &[TorrentFile], piece_count| info.align_with_v1(PIECE_LENGTH, "sample", total as u64, Some(files), piece_count);
+        align(&files, 4).unwrap();
+        assert!(align(&files, 5).is_err());
+
+        // Files that disagree or are not aligned are refused
+        let swapped = vec![files[0].clone(), files[1].clone(), files[3].clone(), files[2].clone()];
+        assert!(matches!(align(&swapped, 4), Err(InvalidTorrentFile::HybridMismatch(_))));
+        let unpadded = vec![files[0].clone(), files[2].clone(), files[3].clone()];
+        assert!(matches!(align(&unpadded, 3), Err(InvalidTorrentFile::HybridMismatch(_))));
+
+        info.align_with_v1(PIECE_LENGTH, "sample", total as u64, Some(&files), 4).unwrap();
+        assert_eq!(info.v1_pieces[2], Some(V2Piece { file: 0, piece: 2, length: big.len() as u64 - 2 * PIECE_LENGTH }));
+        assert_eq!(info.v1_pieces[3], Some(V2Piece { file: 2, piece: 0, length: small.len() as u64 }));
+
+        // Padding after the end of a file is not part of its v2 piece
+        let mut last = big[2 * PIECE_LENGTH as usize..].to_vec();
+        last.resize(PIECE_LENGTH as usize, 0);
+        assert!(info.verify_v1_piece(2, &last));
+        assert!(info.verify_v1_piece(3, &small));
+        assert!(!info.verify_v1_piece(3, b"other file"));
+        assert!(matches!(info.align_with_v1(PIECE_LENGTH * 2, "sample", total as u64, Some(&files), 2), Err(InvalidTorrentFile::HybridMismatch(_))));
+    }
+
+    #[test]
+    fn invalid_v2() {
+        let big = vec![1u8; MERKLE_BLOCK_SIZE * 3];
+        assert_eq!(V2Info::parse(&dict(vec![(b"info", dict(vec![]))]), [0; 32]).unwrap(), None);
+
+        // Piece layer that does not match the root
+        let mut torrent = sample(&big, b"x", true);
+        if let Element::Dict(mp) = &mut torrent {
+            if let Some(Element::Dict(layers)) = mp.get_mut(b"piece layers".as_slice()) {
+                for layer in layers.values_mut() {
+                    *layer = Element::ByteString(vec![0; 64]);
+                }
+            }
+        }
+        assert!(matches!(V2Info::parse(&torrent, [0; 32]), Err(InvalidTorrentFile::InvalidValue("piece layers"))));
+
+        let odd_piece = dict(vec![(b"info", dict(vec![
+            (b"meta version", Element::Integer(2)),
+            (b"piece length", Element::Integer(20000))
+        ]))]);
+        assert!(matches!(V2Info::parse(&odd_piece, [0; 32]), Err(InvalidTorrentFile::InvalidValue("info.piece length"))));
+    }
+}
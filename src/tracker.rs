@@ -1,6 +1,87 @@
-use std::{collections::{VecDeque, HashSet}, sync::Arc};
+use std::{collections::{VecDeque, HashSet}, future::Future, sync::Arc};
+use rand::seq::SliceRandom;
 use tokio::{sync::Mutex, time::{sleep, self}};
-use crate::helpers::CONN_LIMIT;
+use crate::{bitfield::Bitfield, helpers::CONN_LIMIT};
+
+/// Left announced while the size of a torrent is not known yet, nothing left would make trackers take us for a seeder
+pub const UNKNOWN_LENGTH: u64 = 16384;
+
+///Bytes still to download, the length minus the verified pieces with the last one at its real size
+pub fn left(length: u64, piece_length: u64, have: &Bitfield) -> u64 {
+
+    let verified: u64 = have
+        .iter_ones()
+        .map(|piece| piece_length.min(length.saturating_sub(piece as u64 * piece_length)))
+        .sum();
+    length.saturating_sub(verified)
+
+}
+
+/// Trackers grouped into tiers as described in BEP 12.
+/// Tiers are tried in order and the trackers within a tier in order, the first tracker of a
+/// tier that answers is moved to the front of its tier so it is tried first next time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceTiers {
+    tiers: Vec<Vec<String>>
+}
+
+impl AnnounceTiers {
+
+    /// Build tiers from the `announce` and `announce-list` keys, shuffling trackers within each tier.
+    /// `announce` is kept as a last tier if the list does not already contain it
+    pub fn new(announce_url: Option<String>, announce_list: Option<Vec<Vec<String>>>) -> AnnounceTiers {
+
+        let mut tiers: Vec<Vec<String>> = announce_list
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        let mut rng = rand::thread_rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+
+        if let Some(announce_url) = announce_url {
+            if !tiers.iter().flatten().any(|url| *url == announce_url) {
+                tiers.push(vec![announce_url]);
+            }
+        }
+
+        AnnounceTiers { tiers }
+
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Announce to one tracker at a time using `announce`, which returns None if the tracker failed.
+    /// Stops at the first tracker that answers and returns its peers, None if every tracker failed
+    pub async fn announce<F, Fut>(&mut self, mut announce: F) -> Option<Vec<(u32,u16)>>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Option<Vec<(u32,u16)>>>
+    {
+
+        for tier in 0..self.tiers.len() {
+            for ind in 0..self.tiers[tier].len() {
+
+                if let Some(peers) = announce(self.tiers[tier][ind].clone()).await {
+                    // Promote working tracker to the front of its tier
+                    let url = self.tiers[tier].remove(ind);
+                    self.tiers[tier].insert(0, url);
+                    return Some(peers);
+                }
+
+            }
+        }
+
+        None
+
+    }
+
+}
 
 mod udp_tracker {
 
@@ -8,6 +89,9 @@ mod udp_tracker {
     use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
     use url::{Url, Host};
 
+    // Announce attempts per tracker, waiting 15 and then 30 seconds for an answer
+    const ANNOUNCE_ATTEMPTS: u32 = 2;
+
     struct Request {
         connection_id: u64,
         action: u32,
@@ -57,16 +141,16 @@ mod udp_tracker {
     }
 
     // Function to build a request for announce
-    fn build_announce_req(conn_id: u64, info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], downloaded: u64, port: u16) -> (Vec<u8>, u32) {
+    fn build_announce_req(conn_id: u64, info_hash: &[u8; 20], left: u64, peer_id:&[u8;20], downloaded: u64, port: u16) -> (Vec<u8>, u32) {
 
         let req = Request {
             connection_id: conn_id,
             action: 1,
             transaction_id: rand::random(),
-            info_hash: info_hash.clone(),
+            info_hash: *info_hash,
             peer_id: *peer_id,
             downloaded,
-            left: *length,
+            left,
             uploaded: 0,
             event: 0,
             ip_addr: 0,
@@ -98,19 +182,21 @@ mod udp_tracker {
     }
 
     // Convert Url into connect format
-    fn parse_url(announce_url: String) -> (String, String) {
+    fn parse_url(announce_url: String) -> Option<(String, String)> {
 
-        let parsed_url = Url::parse(&announce_url).unwrap();
+        let parsed_url = Url::parse(&announce_url).ok()?;
         let mut remote_addr = String::new();
-        if let Host::Domain(s) = parsed_url.host().unwrap() {
-            remote_addr.push_str(s);
+        match parsed_url.host()? {
+            Host::Domain(s) => remote_addr.push_str(s),
+            Host::Ipv4(ip) => remote_addr.push_str(&ip.to_string()),
+            Host::Ipv6(ip) => remote_addr.push_str(&format!("[{}]", ip))
         }
         remote_addr.push(':');
-        remote_addr.push_str(parsed_url.port().unwrap().to_string().as_mut());
+        remote_addr.push_str(parsed_url.port()?.to_string().as_mut());
 
         // let fin_addr = remote_addr.to_socket_addrs().unwrap().next().unwrap();
 
-        (remote_addr, parsed_url.path().to_owned())
+        Some((remote_addr, parsed_url.path().to_owned()))
     }
 
     // Return action, transaction id, and connection id
@@ -144,9 +230,9 @@ mod udp_tracker {
 
     }
 
-    pub async fn peer_list_helper(info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], announce_url: String, port: u16, downloaded: u64) -> Option<Vec<(u32,u16)>> {
+    pub async fn peer_list_helper(info_hash: &[u8; 20], left: u64, peer_id:&[u8;20], announce_url: String, port: u16, downloaded: u64) -> Option<Vec<(u32,u16)>> {
 
-        let (remote_addr, _path) = parse_url(announce_url);
+        let (remote_addr, _path) = parse_url(announce_url)?;
 
 
         // Connect to remote addr
@@ -162,21 +248,12 @@ mod udp_tracker {
         
 
         // Send Connection request
-        if let Ok(_) = socket.send(&connect_request).await { }
-        else {
+        if socket.send(&connect_request).await.is_err() {
             return None;
         }
 
         // Recieve intital response
-        if let Ok(bytes_read) = timeout(tokio::time::Duration::from_secs(6),socket.recv(&mut res)).await {
-            
-            if let Ok(_) = bytes_read { }
-            else {
-                return None;
-            }
-
-        }
-        else {
+        if !matches!(timeout(tokio::time::Duration::from_secs(6),socket.recv(&mut res)).await, Ok(Ok(_))) {
             return None;
         }
 
@@ -188,19 +265,27 @@ mod udp_tracker {
         }
         
         let mut res = [0; 8192];
-        let (announce_req, announce_transaction_id) = build_announce_req(connection_id, info_hash, length, peer_id, downloaded, port);
+        let (announce_req, announce_transaction_id) = build_announce_req(connection_id, info_hash, left, peer_id, downloaded, port);
         
-        for t in 0..8 {
+        // Retries back off as in BEP 15 but stop early, a dead tracker must not hold up the next one in its tier
+        let mut answered = false;
+        for t in 0..ANNOUNCE_ATTEMPTS {
             // Make announce request
-            socket.send(&announce_req).await.unwrap();
+            if socket.send(&announce_req).await.is_err() {
+                return None;
+            }
             
-            if let Ok(_) = timeout(tokio::time::Duration::from_secs((2u64.pow(t)) * 15),socket.recv(&mut res)).await {
+            if timeout(tokio::time::Duration::from_secs((2u64.pow(t)) * 15),socket.recv(&mut res)).await.is_ok() {
+                answered = true;
                 break;
             }
         }
+        if !answered {
+            return None;
+        }
         
         // Parse Announce Response
-        let resp = parse_announce_resp(&mut res);
+        let resp = parse_announce_resp(&res);
 
         if resp.transaction_id != announce_transaction_id {
             return None;
@@ -215,14 +300,26 @@ mod udp_tracker {
 mod http_tracker {
 
     use byteorder::{BigEndian, ReadBytesExt};
-    use crate::bencoded_parser::Element;
+    use serde::Deserialize;
 
     // use std::str;
+    use std::time::Duration;
     use crate::{
         helpers::u8_to_url,
-        bencoded_parser::Bencode
+        bencoded_parser::{self, DecodeOptions}
     };
 
+    // A tracker that does not answer in time failed, so the next one in its tier is tried
+    const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(15);
+
+    // Announce response, only compact peer lists are supported
+    #[derive(Deserialize)]
+    struct Response {
+        #[serde(default, with = "serde_bytes")]
+        peers: Option<Vec<u8>>
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn url_parser(info_hash: [u8; 20], peer_id:[u8;20], announce_url: String, port: u16, uploaded: u64, downloaded: u64, left: u64, compact: bool, event: &str, numwant: Option<u64>) -> String {
         let mut ret = announce_url + "?" +
             "info_hash=" + &u8_to_url(info_hash.to_owned()) + 
@@ -233,125 +330,159 @@ mod http_tracker {
             "&left=" + &left.to_string() +
             "&compact=" + if compact {"1"} else {"0"} +
             "&event=" + event;
-        if numwant != None {
-            ret.push_str(&("&numwant=".to_owned()+&numwant.unwrap().to_string()));
+        if let Some(numwant) = numwant {
+            ret.push_str(&("&numwant=".to_owned()+&numwant.to_string()));
         }
         ret
     }
 
-    pub async fn peer_list_helper(info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], announce_url: String, port: u16, downloaded: u64) -> Vec<(u32,u16)> {
+    pub async fn peer_list_helper(info_hash: &[u8; 20], left: u64, peer_id:&[u8;20], announce_url: String, port: u16, downloaded: u64) -> Option<Vec<(u32,u16)>> {
         
-        let request = url_parser(info_hash.to_owned(), peer_id.to_owned(), announce_url, port, 0, downloaded, length.to_owned() - downloaded, true, "started", Some(50));
+        let request = url_parser(info_hash.to_owned(), peer_id.to_owned(), announce_url, port, 0, downloaded, left, true, "started", Some(50));
         
-        let res = reqwest::get(request)
+        let res = reqwest::Client::builder()
+                        .timeout(ANNOUNCE_TIMEOUT)
+                        .build()
+                        .ok()?
+                        .get(request)
+                        .send()
                         .await
-                        .unwrap()
+                        .ok()?
                         .bytes()
                         .await
-                        .unwrap()
+                        .ok()?
                         .to_vec();
 
-        let decoded = Bencode::decode_u8(res).unwrap();
-
         let mut ret = Vec::new();
-        let mut peers = Vec::new();
-
-        match decoded {
-            Element::Dict(d) => {
-                if d.contains_key("peers".as_bytes()) {
-                    match &d["peers".as_bytes()] {
-                        Element::ByteString(s) => { peers = s.to_owned(); }
-                        _ => {}
-                    }
-                }
-            }
-            _ => {}
-        }
 
-        for i in (0..peers.len()).step_by(6) {
-            let ip = (&(peers.as_slice())[i..(i+4)]).read_u32::<BigEndian>().unwrap();
-            let po = (&(peers.as_slice())[(i+4)..(i+6)]).read_u16::<BigEndian>().unwrap();
+        // Malformed or oversized tracker response, this tracker failed
+        let peers = match bencoded_parser::from_bytes_with::<Response>(&res, &DecodeOptions::untrusted().lenient()) {
+            Ok(Response { peers: Some(peers) }) => peers,
+            _ => return None
+        };
+
+        for mut peer in peers.chunks_exact(6) {
+            let ip = peer.read_u32::<BigEndian>().unwrap();
+            let po = peer.read_u16::<BigEndian>().unwrap();
             ret.push((ip,po));
         }
 
-        ret
+        Some(ret)
 
     }
 }
 
-async fn peer_list_helper(info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], announce_url: String, port: u16, tor_ref: Arc<Mutex<VecDeque<(u32,u16)>>>, downloaded: Arc<Mutex<u64>>) {
+// Announce to a single tracker, None if it failed or its protocol is not supported
+async fn peer_list_helper(info_hash: &[u8; 20], left: u64, peer_id:&[u8;20], announce_url: String, port: u16, downloaded: Arc<Mutex<u64>>) -> Option<Vec<(u32,u16)>> {
 
-    let mut res = None;
     let download = *downloaded.lock().await;
 
-    if announce_url[0..=5].as_bytes() == "udp://".as_bytes() {
-        res = udp_tracker::peer_list_helper(info_hash, length, peer_id, announce_url, port, download).await;
+    if announce_url.starts_with("udp://") {
+        udp_tracker::peer_list_helper(info_hash, left, peer_id, announce_url, port, download).await
+    }
+    else if announce_url.starts_with("http") {
+        http_tracker::peer_list_helper(info_hash, left, peer_id, announce_url, port, download).await
     }
-    else if announce_url[0..4].as_bytes() == "http".as_bytes() {
-        res = Some(http_tracker::peer_list_helper(info_hash, length, peer_id, announce_url, port, download).await);
+    else {
+        None
     }
+}
 
-    if let Some(peers) = res {
-        
-        let mut tor = tor_ref.lock().await;
-        for peer in peers {
-            (*tor).push_back(peer);
+// Function to get peer list
+/// Announce once, trying trackers tier by tier until one answers
+pub async fn announce(info_hash: [u8; 20], left: u64, peer_id: [u8;20], tiers: &mut AnnounceTiers, downloaded: Arc<Mutex<u64>>) -> Option<Vec<(u32,u16)>> {
+
+    let port: u16 = 6881;
+
+    tiers.announce(|announce_url| {
+        let downloaded = downloaded.clone();
+        async move {
+            peer_list_helper(&info_hash, left, &peer_id, announce_url, port, downloaded).await
         }
+    }).await
 
-    }
 }
 
-// Function to get peer list
-pub async fn get_peers(info_hash: [u8; 20], length: u64, peer_id: [u8;20], announce_url: Option<String>, peer_list: Arc<Mutex<VecDeque<(u32, u16)>>>, announce_list: Option<Vec<String>>, connections: Arc<Mutex<HashSet<(u32,u16)>>>, downloaded: Arc<Mutex<u64>>, piece_left: Arc<Mutex<u16>>) {
+// Hybrid torrents are announced under each of their info hashes
+#[allow(clippy::too_many_arguments)]
+pub async fn get_peers(info_hashes: Vec<[u8; 20]>, length: u64, piece_length: u64, peer_id: [u8;20], mut tiers: AnnounceTiers, peer_list: Arc<Mutex<VecDeque<(u32, u16)>>>, connections: Arc<Mutex<HashSet<(u32,u16)>>>, downloaded: Arc<Mutex<u64>>, have: Arc<Mutex<Bitfield>>) {
 
     loop {
-        if *(piece_left.lock().await) == 0 {
+        if have.lock().await.is_complete() {
             break;
         }
 
-        while (*(connections.lock().await)).len() as u32 >= CONN_LIMIT || !peer_list.lock().await.is_empty() {}
+        while (*(connections.lock().await)).len() as u32 >= CONN_LIMIT || !peer_list.lock().await.is_empty() {
+            sleep(time::Duration::from_millis(1000)).await;
+        }
 
-        // Create udp socket
-        let mut port: u16 = 6881;
-        let mut handles = vec![];
+        let left = left(length, piece_length, &*have.lock().await);
+        for info_hash in &info_hashes {
+            let peers = announce(*info_hash, left, peer_id, &mut tiers, downloaded.clone()).await;
 
-        // Check for announce_url and announce_list
-        if let Some(announce_url) = announce_url.clone() {
-            
-            let tor_ref = peer_list.clone();
-            let downloaded = downloaded.clone();
+            if let Some(peers) = peers {
+                let mut tor = peer_list.lock().await;
+                for peer in peers {
+                    (*tor).push_back(peer);
+                }
+            }
+        }
 
-            let h = tokio::spawn(async move{
-                peer_list_helper(&info_hash, &length, &peer_id, announce_url, port, tor_ref, downloaded).await;
-            });   
+        sleep(time::Duration::from_secs(5)).await;
+    }
+}
 
-            handles.push(h);
-            port += 1;
-            
-        }
+#[cfg(test)]
+mod tests {
+    use crate::bitfield::Bitfield;
+    use super::{left, AnnounceTiers};
 
-        if let Some(announce_list) = announce_list.clone() {
+    fn tiers(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers.iter().map(|tier| tier.iter().map(|url| url.to_string()).collect()).collect()
+    }
 
-            for announce_url in announce_list {
-                
-                let tor_ref = peer_list.clone();
-                let downloaded = downloaded.clone();
+    #[test]
+    fn announce_url_kept_as_last_tier() {
+        let list = tiers(&[&["a"], &[], &["b"]]);
+        assert_eq!(AnnounceTiers::new(Some("c".to_string()), Some(list.clone())).tiers(), tiers(&[&["a"], &["b"], &["c"]]));
+        assert_eq!(AnnounceTiers::new(Some("b".to_string()), Some(list)).tiers(), tiers(&[&["a"], &["b"]]));
+        assert_eq!(AnnounceTiers::new(Some("c".to_string()), None).tiers(), tiers(&[&["c"]]));
+    }
 
-                let h = tokio::spawn(async move{
-                    peer_list_helper(&info_hash, &length, &peer_id, announce_url, port, tor_ref, downloaded).await;
-                });   
+    #[tokio::test]
+    async fn announce_falls_back_and_promotes() {
+        let mut announce_tiers = AnnounceTiers { tiers: tiers(&[&["dead1", "dead2"], &["dead3", "live1", "live2"]]) };
 
-                handles.push(h);
-                port += 1;
-                
-            }
-            
-        }
-        
-        for handle in handles {
-            handle.await.unwrap();
-        }
+        // Whole first tier fails, second tier is tried in order until one answers
+        let mut tried = Vec::new();
+        let peers = announce_tiers.announce(|url| {
+            tried.push(url.clone());
+            async move { url.starts_with("live").then(|| vec![(1, 6881)]) }
+        }).await;
 
-        sleep(time::Duration::from_secs(5)).await;
+        assert_eq!(peers, Some(vec![(1, 6881)]));
+        assert_eq!(tried, vec!["dead1", "dead2", "dead3", "live1"]);
+        assert_eq!(announce_tiers.tiers(), tiers(&[&["dead1", "dead2"], &["live1", "dead3", "live2"]]));
+
+        // Nothing answers
+        assert_eq!(announce_tiers.announce(|_| async { None }).await, None);
+    }
+
+    #[test]
+    fn left_counts_verified_pieces() {
+        // Three whole pieces and a last one of 100 bytes
+        let mut have = Bitfield::new(4);
+        assert_eq!(left(49252, 16384, &have), 49252);
+
+        have.set(1, true);
+        assert_eq!(left(49252, 16384, &have), 32868);
+
+        // The short last piece only counts its real size
+        have.set(3, true);
+        assert_eq!(left(49252, 16384, &have), 32768);
+
+        have.set(0, true);
+        have.set(2, true);
+        assert_eq!(left(49252, 16384, &have), 0);
     }
-}
\ No newline at end of file
+}
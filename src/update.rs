@@ -0,0 +1,185 @@
+use std::{fmt, time::Duration};
+use tokio::time;
+use crate::torrent_parser::{InvalidTorrentFile, Metainfo};
+
+/// How often the update-url of a torrent is fetched
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// Longest a single fetch of the update-url may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Reason the update-url of a torrent did not give a usable torrent
+#[derive(Debug)]
+pub enum UpdateError {
+    // Request failed or the server did not answer with success
+    Fetch(reqwest::Error),
+    Torrent(InvalidTorrentFile),
+    // Torrent served is not a version of ours, names the key that differs
+    Mismatch(&'static str)
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::Fetch(e) => write!(f, "Could not fetch update: {}", e),
+            UpdateError::Torrent(e) => write!(f, "Update is not usable: {}", e),
+            UpdateError::Mismatch(key) => write!(f, "Update is not a version of this torrent, {} differs", key)
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+impl From<reqwest::Error> for UpdateError {
+    fn from(e: reqwest::Error) -> Self {
+        UpdateError::Fetch(e)
+    }
+}
+
+impl From<InvalidTorrentFile> for UpdateError {
+    fn from(e: InvalidTorrentFile) -> Self {
+        UpdateError::Torrent(e)
+    }
+}
+
+///Fetch the update-url of a torrent once (BEP 39)
+///None if it has no update-url or the feed does not serve a strictly newer version
+///A newer version must come from the same originator, keep the same update-url and have a later creation date
+pub async fn check(metainfo: &Metainfo) -> Result<Option<Metainfo>, UpdateError> {
+
+    let Some(url) = &metainfo.update_url else {
+        return Ok(None);
+    };
+
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let buf = client.get(url).send().await?.error_for_status()?.bytes().await?;
+    let latest = Metainfo::from_bytes(&buf)?;
+
+    if latest.info_hash == metainfo.info_hash {
+        return Ok(None);
+    }
+    if latest.originator != metainfo.originator {
+        return Err(UpdateError::Mismatch("originator"));
+    }
+    if latest.update_url != metainfo.update_url {
+        return Err(UpdateError::Mismatch("update-url"));
+    }
+    // Without both dates there is no telling a rolled back feed from a newer version
+    match (metainfo.creation_date, latest.creation_date) {
+        (Some(ours), Some(theirs)) if theirs > ours => Ok(Some(latest)),
+        _ => Ok(None)
+    }
+
+}
+
+///Fetch the update-url right away and then every interval until the feed serves a newer version
+///Failures are passed to on_error and retried, never returns for a torrent without update-url
+pub async fn wait_for_update(metainfo: &Metainfo, interval: Duration, mut on_error: impl FnMut(UpdateError)) -> Metainfo {
+
+    loop {
+        match check(metainfo).await {
+            Ok(Some(latest)) => return latest,
+            Ok(None) => (),
+            Err(e) => on_error(e)
+        }
+        time::sleep(interval).await;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, time};
+    use crate::torrent_parser::Metainfo;
+    use super::{check, wait_for_update, UpdateError};
+
+    // Stand-in for the feed, its url has to be known before the torrents naming it are made
+    async fn feed() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/feed", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    // Answer one request per response, in order
+    fn serve(listener: TcpListener, responses: Vec<(&'static str, Vec<u8>)>) {
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+    }
+
+    fn torrent(url: &str, originator: &str, date: i64, piece: char) -> Vec<u8> {
+        let date = if date < 0 { String::new() } else { format!("13:creation datei{}e", date) };
+        format!(
+            "d8:announce3:url{}4:infod6:lengthi1e4:name1:x10:originator{}:{}12:piece lengthi16384e\
+             6:pieces20:{}10:update-url{}:{}ee",
+            date, originator.len(), originator, piece.to_string().repeat(20), url.len(), url
+        ).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn check_feed() {
+        let (listener, url) = feed().await;
+        let ours = Metainfo::from_bytes(&torrent(&url, "key", 2, 'a')).unwrap();
+        let newer = torrent(&url, "key", 3, 'b');
+        serve(listener, vec![
+            ("200 OK", torrent(&url, "key", 2, 'a')),
+            ("200 OK", torrent(&url, "key", 1, 'b')),
+            ("200 OK", torrent(&url, "other", 3, 'b')),
+            ("200 OK", torrent("http://elsewhere", "key", 3, 'b')),
+            ("404 Not Found", Vec::new()),
+            ("200 OK", torrent(&url, "key", -1, 'b')),
+            ("200 OK", torrent(&url, "key", 2, 'b')),
+            ("200 OK", newer.clone())
+        ]);
+
+        // Same version and an older one are no update
+        assert_eq!(check(&ours).await.unwrap(), None);
+        assert_eq!(check(&ours).await.unwrap(), None);
+
+        assert!(matches!(check(&ours).await, Err(UpdateError::Mismatch("originator"))));
+        assert!(matches!(check(&ours).await, Err(UpdateError::Mismatch("update-url"))));
+        assert!(matches!(check(&ours).await, Err(UpdateError::Fetch(_))));
+        // Neither a missing nor an equal creation date makes a version newer
+        assert_eq!(check(&ours).await.unwrap(), None);
+        assert_eq!(check(&ours).await.unwrap(), None);
+        assert_eq!(check(&ours).await.unwrap(), Some(Metainfo::from_bytes(&newer).unwrap()));
+
+        // Nothing to fetch without update-url
+        assert_eq!(check(&Metainfo { update_url: None, ..ours }).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn wait_for_newer_version() {
+        let (listener, url) = feed().await;
+        let ours = Metainfo::from_bytes(&torrent(&url, "key", 2, 'a')).unwrap();
+        let newer = torrent(&url, "key", 3, 'b');
+        serve(listener, vec![
+            ("500 Internal Server Error", Vec::new()),
+            ("200 OK", torrent(&url, "key", 2, 'a')),
+            ("200 OK", newer.clone())
+        ]);
+
+        let mut errors = 0;
+        let latest = wait_for_update(&ours, Duration::from_millis(10), |_| errors += 1).await;
+        assert_eq!(latest.info_hash, Metainfo::from_bytes(&newer).unwrap().info_hash);
+        assert_eq!(errors, 1);
+    }
+
+    #[tokio::test]
+    async fn check_before_first_interval() {
+        let (listener, url) = feed().await;
+        let ours = Metainfo::from_bytes(&torrent(&url, "key", 2, 'a')).unwrap();
+        serve(listener, vec![("200 OK", torrent(&url, "key", 3, 'b'))]);
+
+        // An update already published is found without waiting a whole interval
+        let latest = time::timeout(Duration::from_secs(5), wait_for_update(&ours, Duration::from_secs(3600), |_| ()));
+        assert!(latest.await.is_ok());
+    }
+}